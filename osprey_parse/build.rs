@@ -0,0 +1,153 @@
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn main() {
+    let manifest_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    println!("cargo:rerun-if-changed=Cargo.toml");
+
+    // The crates whose behaviour ends up in `roc_parse_results`: every path
+    // dependency, and theirs in turn, since a change to e.g. roc_region's
+    // offsets matters as much as one to roc_parse itself. Their sources are
+    // hashed into PARSER_BUILD_ID, so that `parse --incremental` re-parses
+    // everything whenever any of them changes, and nothing otherwise.
+    let mut crates = BTreeMap::new();
+    collect_path_dependencies(&manifest_dir, &mut crates);
+
+    let mut hasher = Sha256::new();
+    for (name, crate_dir) in &crates {
+        let mut files = Vec::new();
+        collect_files(crate_dir, &mut files);
+        files.sort();
+
+        for file in files {
+            let relative = file.strip_prefix(crate_dir).unwrap();
+            hasher.update(name.as_bytes());
+            hasher.update(relative.to_string_lossy().as_bytes());
+            hasher.update(fs::read(&file).unwrap());
+        }
+    }
+
+    let build_id = format!("{:x}", hasher.finalize());
+    println!("cargo:rustc-env=PARSER_BUILD_ID={}", &build_id[..16]);
+
+    let roc_parse_dir = crates
+        .get("roc_parse")
+        .expect("no path dependency on roc_parse in Cargo.toml");
+    println!(
+        "cargo:rustc-env=ROC_REVISION={}",
+        roc_revision(roc_parse_dir)
+    );
+}
+
+fn roc_revision(dir: &Path) -> String {
+    // Cargo only re-runs this for changes to the sources, which a commit or
+    // checkout that leaves them alone wouldn't make, so watch what `git
+    // describe` reads too.
+    if let Some(git_dir) = git_dir(dir) {
+        let head = git_dir.join("HEAD");
+        println!("cargo:rerun-if-changed={}", head.display());
+        if let Some(head_ref) = fs::read_to_string(&head)
+            .ok()
+            .and_then(|head| Some(head.strip_prefix("ref:")?.trim().to_string()))
+        {
+            // Watching a file that doesn't exist would re-run this on every
+            // build, and the ref may be loose, packed or both.
+            for path in [git_dir.join(head_ref), git_dir.join("packed-refs")] {
+                if path.exists() {
+                    println!("cargo:rerun-if-changed={}", path.display());
+                }
+            }
+        }
+    }
+
+    Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["describe", "--always", "--dirty"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|revision| revision.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn git_dir(dir: &Path) -> Option<PathBuf> {
+    Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--absolute-git-dir"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|git_dir| PathBuf::from(git_dir.trim()))
+}
+
+// Adds the crate at `dir`'s path dependencies to `crates`, by name, and then
+// theirs. Crates already in `crates` are not visited again.
+fn collect_path_dependencies(dir: &Path, crates: &mut BTreeMap<String, PathBuf>) {
+    let manifest = fs::read_to_string(dir.join("Cargo.toml")).unwrap();
+    for (name, path) in path_dependencies(&manifest) {
+        let crate_dir = dir.join(path);
+        if crates.contains_key(&name) {
+            continue;
+        }
+        println!("cargo:rerun-if-changed={}", crate_dir.display());
+        crates.insert(name, crate_dir.clone());
+        collect_path_dependencies(&crate_dir, crates);
+    }
+}
+
+// Pulls `name` and `path = "..."` out of each `name = { path = "..." }` line
+// in the manifest's `[dependencies]` tables. Dev and build dependencies don't
+// change what the parser does, so they're left out.
+fn path_dependencies(manifest: &str) -> Vec<(String, String)> {
+    let mut in_dependencies = false;
+    let mut dependencies = Vec::new();
+    for line in manifest.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_dependencies = line == "[dependencies]"
+                || (line.starts_with("[target.") && line.ends_with(".dependencies]"));
+            continue;
+        }
+        if !in_dependencies {
+            continue;
+        }
+        let Some((name, rest)) = line.split_once('=') else {
+            continue;
+        };
+        if let Some(path) = quoted_path(rest) {
+            dependencies.push((name.trim().to_string(), path));
+        }
+    }
+    dependencies
+}
+
+// The `"..."` after `path` in a dependency's spec, if it has one.
+fn quoted_path(spec: &str) -> Option<String> {
+    let rest = &spec[spec.find("path")? + "path".len()..];
+    let start = rest.find('"')? + 1;
+    let end = start + rest[start..].find('"')?;
+    Some(rest[start..end].to_string())
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            if path.file_name().is_some_and(|n| n == "target") {
+                continue;
+            }
+            collect_files(&path, files);
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext == "rs" || ext == "toml")
+        {
+            files.push(path);
+        }
+    }
+}
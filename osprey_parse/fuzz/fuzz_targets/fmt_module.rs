@@ -0,0 +1,25 @@
+#![no_main]
+
+// `parse_one` on each input, crashing on the first failure it records, so
+// the fuzzer looks for exactly what `parse` reports. Input that doesn't parse
+// is of no interest.
+//
+// Seed the fuzzer from the corpus (leaving out files that already fail, or it
+// would stop on the first of them), run it, and read what it found back in as
+// a run like any other:
+//
+//     osprey_parse export-fuzz-corpus -c corpus.db --zulip-db zulip_code_blocks.db \
+//         -o fuzz/corpus/fmt_module
+//     cargo fuzz run fmt_module
+//     osprey_parse parse --corpus-fuzz-artifacts fuzz/artifacts -r results.db
+
+use libfuzzer_sys::fuzz_target;
+use osprey_parse::minimize;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(input) = std::str::from_utf8(data) {
+        if let Some(failure) = minimize::failures(&osprey_parse::parse_one(input)).first() {
+            panic!("{}", failure.describe());
+        }
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use bumpalo::Bump;
+use libfuzzer_sys::fuzz_target;
+
+// The parser on its own, which gets through inputs much faster than
+// `fmt_module` when it's parser panics and hangs being looked for.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(input) = std::str::from_utf8(data) {
+        let arena = Bump::new();
+        let _ = osprey_parse::parse_any(input, &arena);
+    }
+});
@@ -0,0 +1,417 @@
+// Structural comparison of the `{:#?}` dumps that `parse_one` records.
+//
+// roc_parse's AST has a few hundred node types, so rather than walking it
+// directly this reads the Debug output back into a generic tree, using the
+// same grammar as crates/debug_parse: structs, tuple structs, tuples, lists,
+// strings, integers, and `@start-end` regions, which prefix located nodes.
+// Normalized ASTs have all their regions zeroed, which Debug prints as `…`.
+
+use serde::Serialize;
+use std::fmt::Write;
+
+#[derive(Debug, PartialEq)]
+enum Node<'a> {
+    Leaf(&'a str),
+    Region(usize, usize),
+    Tuple(Vec<Node<'a>>),
+    List(Vec<Node<'a>>),
+    Struct(&'a str, Fields<'a>),
+    Loc(usize, usize, Box<Node<'a>>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Fields<'a> {
+    Unit,
+    Tuple(Vec<Node<'a>>),
+    Struct(Vec<(&'a str, Node<'a>)>),
+}
+
+struct Reader<'a> {
+    text: &'a str,
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn rest(&self) -> &'a [u8] {
+        &self.text.as_bytes()[self.offset..]
+    }
+
+    fn consume_ws(&mut self) {
+        while self.rest().first().is_some_and(u8::is_ascii_whitespace) {
+            self.offset += 1;
+        }
+    }
+
+    fn check(&mut self, next: &str) -> bool {
+        if self.rest().starts_with(next.as_bytes()) {
+            self.offset += next.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(u8) -> bool) -> &'a str {
+        let start = self.offset;
+        while self.rest().first().is_some_and(|&c| f(c)) {
+            self.offset += 1;
+        }
+        &self.text[start..self.offset]
+    }
+
+    fn int(&mut self) -> Option<usize> {
+        self.take_while(|c| c.is_ascii_digit()).parse().ok()
+    }
+
+    fn at_terminator(&self) -> bool {
+        matches!(self.rest().first(), None | Some(b',' | b')' | b']' | b'}'))
+    }
+
+    fn seq<T>(
+        &mut self,
+        end: &str,
+        mut item: impl FnMut(&mut Self) -> Option<T>,
+    ) -> Option<Vec<T>> {
+        let mut items = Vec::new();
+        loop {
+            self.consume_ws();
+            if self.check(end) {
+                return Some(items);
+            }
+            items.push(item(self)?);
+            self.consume_ws();
+            if !self.check(",") && !self.rest().starts_with(end.as_bytes()) {
+                return None;
+            }
+        }
+    }
+
+    fn node(&mut self) -> Option<Node<'a>> {
+        self.consume_ws();
+        let start = self.offset;
+
+        if self.check("\"") {
+            loop {
+                match self.rest().first()? {
+                    b'\\' => self.offset += 2,
+                    b'"' => {
+                        self.offset += 1;
+                        return Some(Node::Leaf(&self.text[start..self.offset]));
+                    }
+                    _ => self.offset += 1,
+                }
+            }
+        } else if self.check("…") {
+            Some(Node::Leaf("…"))
+        } else if self.check("@") {
+            let region_start = self.int()?;
+            let region_end = if self.check("-") {
+                self.int()?
+            } else {
+                region_start
+            };
+            self.consume_ws();
+            if self.at_terminator() {
+                Some(Node::Region(region_start, region_end))
+            } else {
+                let inner = self.node()?;
+                Some(Node::Loc(region_start, region_end, Box::new(inner)))
+            }
+        } else if self.rest().first()?.is_ascii_digit() || self.check("-") {
+            self.take_while(|c| c.is_ascii_alphanumeric() || c == b'.' || c == b'_');
+            Some(Node::Leaf(&self.text[start..self.offset]))
+        } else if self.check("(") {
+            self.seq(")", Self::node).map(Node::Tuple)
+        } else if self.check("[") {
+            self.seq("]", Self::node).map(Node::List)
+        } else {
+            let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b':');
+            if name.is_empty() {
+                return None;
+            }
+            // Generic parameters only show up on a few helper types and
+            // never distinguish two nodes.
+            if self.check("<") {
+                self.take_while(|c| c != b'>');
+                self.check(">");
+            }
+            self.consume_ws();
+            let fields = if self.check("(") {
+                Fields::Tuple(self.seq(")", Self::node)?)
+            } else if self.check("{") {
+                Fields::Struct(self.seq("}", |r| {
+                    r.consume_ws();
+                    let key = r.take_while(|c| c.is_ascii_alphanumeric() || c == b'_');
+                    r.consume_ws();
+                    if !r.check(":") {
+                        return None;
+                    }
+                    Some((key, r.node()?))
+                })?)
+            } else {
+                Fields::Unit
+            };
+            Some(Node::Struct(name, fields))
+        }
+    }
+}
+
+fn read(text: &str) -> Option<Node<'_>> {
+    let mut reader = Reader { text, offset: 0 };
+    let node = reader.node()?;
+    reader.consume_ws();
+    (reader.offset == text.len()).then_some(node)
+}
+
+#[derive(Clone, Debug)]
+enum Step<'a> {
+    Variant(&'a str),
+    Field(&'a str),
+    Element(usize),
+}
+
+fn render_path(steps: &[Step]) -> String {
+    let mut path = String::new();
+    for step in steps {
+        match step {
+            Step::Variant(name) if path.is_empty() => path.push_str(name),
+            Step::Variant(name) => write!(path, ".{}", name).unwrap(),
+            Step::Field(name) => write!(path, ".{}", name).unwrap(),
+            Step::Element(i) => write!(path, "[{}]", i).unwrap(),
+        }
+    }
+    path
+}
+
+fn kind<'a>(node: &Node<'a>) -> &'a str {
+    match node {
+        Node::Leaf(_) => "value",
+        Node::Region(..) => "region",
+        Node::Tuple(_) => "tuple",
+        Node::List(_) => "list",
+        Node::Struct(name, _) => name,
+        Node::Loc(_, _, inner) => kind(inner),
+    }
+}
+
+// Depth-first, so the divergence reported is the first in source order,
+// and as deep as possible: two lists that differ in one element diverge at
+// that element, not at the list.
+fn diverge<'a>(a: &Node<'a>, b: &Node<'a>, path: &mut Vec<Step<'a>>) -> Option<(String, String)> {
+    let differs = || Some((kind(a).to_string(), kind(b).to_string()));
+    match (a, b) {
+        (Node::Loc(_, _, a), _) => diverge(a, b, path),
+        (_, Node::Loc(_, _, b)) => diverge(a, b, path),
+        (Node::Region(..), Node::Region(..)) => None,
+        (Node::Leaf(x), Node::Leaf(y)) => (x != y).then(differs)?,
+        (Node::Tuple(xs), Node::Tuple(ys)) | (Node::List(xs), Node::List(ys)) => {
+            diverge_all(xs, ys, path).or_else(|| (xs.len() != ys.len()).then(differs)?)
+        }
+        (Node::Struct(x, xf), Node::Struct(y, yf)) if x == y => {
+            path.push(Step::Variant(x));
+            let found = match (xf, yf) {
+                (Fields::Unit, Fields::Unit) => None,
+                (Fields::Tuple(xs), Fields::Tuple(ys)) => {
+                    diverge_all(xs, ys, path).or_else(|| (xs.len() != ys.len()).then(differs)?)
+                }
+                (Fields::Struct(xs), Fields::Struct(ys)) => {
+                    xs.iter().zip(ys).find_map(|((xk, xv), (yk, yv))| {
+                        if xk != yk {
+                            return differs();
+                        }
+                        path.push(Step::Field(xk));
+                        let found = diverge(xv, yv, path);
+                        if found.is_none() {
+                            path.pop();
+                        }
+                        found
+                    })
+                }
+                _ => differs(),
+            };
+            if found.is_none() {
+                path.pop();
+            }
+            found
+        }
+        _ => differs(),
+    }
+}
+
+fn diverge_all<'a>(
+    xs: &[Node<'a>],
+    ys: &[Node<'a>],
+    path: &mut Vec<Step<'a>>,
+) -> Option<(String, String)> {
+    xs.iter().zip(ys).enumerate().find_map(|(i, (x, y))| {
+        path.push(Step::Element(i));
+        let found = diverge(x, y, path);
+        if found.is_none() {
+            path.pop();
+        }
+        found
+    })
+}
+
+// Follows a path found in a normalized tree through the unnormalized one,
+// which has the regions. Normalization drops whitespace and parens, so those
+// wrappers are stepped through. Returns the innermost region passed.
+fn locate(mut node: &Node, steps: &[Step]) -> Option<(usize, usize)> {
+    let mut region = None;
+    let mut steps = steps.iter().peekable();
+    loop {
+        loop {
+            match node {
+                Node::Loc(start, end, inner) => {
+                    region = Some((*start, *end));
+                    node = inner;
+                }
+                Node::Struct(
+                    "SpaceBefore" | "SpaceAfter" | "ParensAround",
+                    Fields::Tuple(fields),
+                ) if !matches!(steps.peek(), Some(Step::Variant(name)) if *name == kind(node)) => {
+                    node = fields.first()?;
+                }
+                _ => break,
+            }
+        }
+
+        let Some(step) = steps.next() else {
+            return region;
+        };
+        node = match (step, node) {
+            (Step::Variant(name), Node::Struct(actual, _)) if name == actual => continue,
+            (Step::Field(name), Node::Struct(_, Fields::Struct(fields))) => {
+                match fields.iter().find(|(key, _)| key == name) {
+                    Some((_, value)) => value,
+                    None => return region,
+                }
+            }
+            (Step::Element(i), Node::Struct(_, Fields::Tuple(elements)))
+            | (Step::Element(i), Node::Tuple(elements))
+            | (Step::Element(i), Node::List(elements)) => match elements.get(*i) {
+                Some(element) => element,
+                None => return region,
+            },
+            _ => return region,
+        };
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Divergence {
+    pub path: String,
+    // `Before -> After` when the node itself was replaced, otherwise the kind
+    // of the node whose contents differ
+    pub kind: String,
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+    pub fmt_start: Option<usize>,
+    pub fmt_end: Option<usize>,
+}
+
+// `None` if the dumps are the same or can't be read back.
+pub fn first_divergence(
+    normalized: &str,
+    normalized_reparse: &str,
+    output: &str,
+    reparse_output: &str,
+) -> Option<Divergence> {
+    let a = read(normalized)?;
+    let b = read(normalized_reparse)?;
+    let mut steps = Vec::new();
+    let (kind_a, kind_b) = diverge(&a, &b, &mut steps)?;
+
+    let region = read(output).and_then(|tree| locate(&tree, &steps));
+    let fmt_region = read(reparse_output).and_then(|tree| locate(&tree, &steps));
+
+    // A differing string or number is reported as the node holding it.
+    let enclosing = steps.iter().rev().find_map(|step| match step {
+        Step::Variant(name) => Some(name.to_string()),
+        _ => None,
+    });
+    Some(Divergence {
+        path: render_path(&steps),
+        kind: if kind_a == kind_b {
+            match enclosing {
+                Some(name) if kind_a == "value" => name,
+                _ => kind_a,
+            }
+        } else {
+            format!("{} -> {}", kind_a, kind_b)
+        },
+        start: region.map(|r| r.0),
+        end: region.map(|r| r.1),
+        fmt_start: fmt_region.map(|r| r.0),
+        fmt_end: fmt_region.map(|r| r.1),
+    })
+}
+
+const EXCERPT_WIDTH: usize = 60;
+
+// The lines spanned by `region` in each source, next to each other.
+pub fn side_by_side(
+    original: &str,
+    region: Option<(usize, usize)>,
+    formatted: &str,
+    fmt_region: Option<(usize, usize)>,
+) -> String {
+    let left = excerpt(original, region);
+    let right = excerpt(formatted, fmt_region);
+
+    let mut out = String::new();
+    writeln!(out, "{:<w$} | formatted", "original", w = EXCERPT_WIDTH + 7).unwrap();
+    for i in 0..left.len().max(right.len()) {
+        let cell = |lines: &[(usize, String)]| match lines.get(i) {
+            Some((n, line)) => format!("{:>5}  {}", n, truncate(line, EXCERPT_WIDTH)),
+            None => String::new(),
+        };
+        writeln!(
+            out,
+            "{:<w$} | {}",
+            cell(&left),
+            cell(&right),
+            w = EXCERPT_WIDTH + 7
+        )
+        .unwrap();
+    }
+    out
+}
+
+// Caps an excerpt so a divergence in a huge top-level def stays readable.
+const EXCERPT_MAX_LINES: usize = 12;
+
+fn excerpt(source: &str, region: Option<(usize, usize)>) -> Vec<(usize, String)> {
+    let Some((start, end)) = region else {
+        return Vec::new();
+    };
+    let start = start.min(source.len());
+    let end = end.clamp(start, source.len());
+    let first_line = source.as_bytes()[..start]
+        .iter()
+        .filter(|&&c| c == b'\n')
+        .count();
+    let line_count = source.as_bytes()[start..end]
+        .iter()
+        .filter(|&&c| c == b'\n')
+        .count()
+        + 1;
+
+    source
+        .lines()
+        .enumerate()
+        .skip(first_line)
+        .take(line_count.min(EXCERPT_MAX_LINES))
+        .map(|(i, line)| (i + 1, line.to_string()))
+        .collect()
+}
+
+fn truncate(line: &str, width: usize) -> String {
+    if line.chars().count() <= width {
+        line.to_string()
+    } else {
+        let mut s: String = line.chars().take(width - 1).collect();
+        s.push('…');
+        s
+    }
+}
@@ -0,0 +1,227 @@
+// Comment preservation. `remove_spaces` drops comments along with the rest
+// of the whitespace, so the normalized AST comparison can't see a formatter
+// that loses or duplicates one; this compares them separately.
+//
+// Comments are lexed from the source and from `fmt_output` rather than read
+// from the parser's output, so the check still works when the formatted code
+// doesn't reparse, and also catches comments the parser itself dropped. The
+// lexer only knows enough roc to not mistake a `#` inside a string or char
+// literal (interpolations included) for a comment.
+
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+use std::collections::HashMap;
+
+enum Context {
+    // Top-level code, or the inside of a string interpolation, which ends at
+    // the `)` matching its opening one
+    Code { parens: usize },
+    Str,
+    BlockStr,
+}
+
+// Each comment as it would be written, e.g. `## Docs`, in source order.
+pub fn comments(source: &str) -> Vec<String> {
+    let bytes = source.as_bytes();
+    let mut found = Vec::new();
+    let mut stack = vec![Context::Code { parens: 0 }];
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        let depth = stack.len();
+        match stack.last_mut().unwrap() {
+            Context::Code { parens } => match rest[0] {
+                b'#' => {
+                    let end = source[i..].find('\n').map_or(source.len(), |n| i + n);
+                    let comment = &source[i..end];
+                    // The formatter normalizes the space after `#` and strips
+                    // trailing whitespace, neither of which loses anything.
+                    found.push(match comment.strip_prefix("##") {
+                        Some(text) => format!("## {}", text.trim()),
+                        None => format!("# {}", comment[1..].trim()),
+                    });
+                    i = end;
+                }
+                b'"' if rest.starts_with(b"\"\"\"") => {
+                    stack.push(Context::BlockStr);
+                    i += 3;
+                }
+                b'"' => {
+                    stack.push(Context::Str);
+                    i += 1;
+                }
+                b'\'' => {
+                    i += 1;
+                    while i < bytes.len() && !matches!(bytes[i], b'\'' | b'\n') {
+                        i += if bytes[i] == b'\\' { 2 } else { 1 };
+                    }
+                    i += 1;
+                }
+                b'(' => {
+                    *parens += 1;
+                    i += 1;
+                }
+                b')' if *parens == 0 && depth > 1 => {
+                    stack.pop();
+                    i += 1;
+                }
+                b')' => {
+                    *parens = parens.saturating_sub(1);
+                    i += 1;
+                }
+                _ => i += 1,
+            },
+            Context::Str | Context::BlockStr => {
+                let block = matches!(stack.last(), Some(Context::BlockStr));
+                if rest.starts_with(b"$(") || rest.starts_with(b"\\(") {
+                    stack.push(Context::Code { parens: 0 });
+                    i += 2;
+                } else if rest[0] == b'\\' {
+                    i += 2;
+                } else if block && rest.starts_with(b"\"\"\"") {
+                    stack.pop();
+                    i += 3;
+                } else if !block && matches!(rest[0], b'"' | b'\n') {
+                    // A string left open at the end of the line is an error
+                    // the parser reports; resume lexing code after it.
+                    stack.pop();
+                    i += 1;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+    found
+}
+
+#[derive(Debug, Default)]
+pub struct CommentCheck {
+    // In the original but not the formatted output
+    pub lost: Vec<String>,
+    // In the formatted output more often than in the original (including not
+    // at all)
+    pub duplicated: Vec<String>,
+    // In both as often, but in a different order relative to the others
+    pub moved: Vec<String>,
+}
+
+impl CommentCheck {
+    pub fn preserved(&self) -> bool {
+        self.lost.is_empty() && self.duplicated.is_empty() && self.moved.is_empty()
+    }
+}
+
+pub fn check_comments(original: &[String], formatted: &[String]) -> CommentCheck {
+    let mut balance: HashMap<&str, i64> = HashMap::new();
+    for comment in original {
+        *balance.entry(comment).or_default() += 1;
+    }
+    for comment in formatted {
+        *balance.entry(comment).or_default() -= 1;
+    }
+
+    let mut check = CommentCheck::default();
+    let mut remaining = balance.clone();
+    for comment in original {
+        let n = remaining.get_mut(comment.as_str()).unwrap();
+        if *n > 0 {
+            *n -= 1;
+            check.lost.push(comment.clone());
+        }
+    }
+    for comment in formatted {
+        let n = remaining.get_mut(comment.as_str()).unwrap();
+        if *n < 0 {
+            *n += 1;
+            check.duplicated.push(comment.clone());
+        }
+    }
+
+    // Whatever falls outside the longest common subsequence was moved, once
+    // the count mismatches above are accounted for.
+    let mut unmatched: HashMap<&str, i64> = HashMap::new();
+    for op in capture_diff_slices(Algorithm::Myers, original, formatted) {
+        if let DiffOp::Delete {
+            old_index, old_len, ..
+        }
+        | DiffOp::Replace {
+            old_index, old_len, ..
+        } = op
+        {
+            for comment in &original[old_index..old_index + old_len] {
+                *unmatched.entry(comment).or_default() += 1;
+            }
+        }
+    }
+    for comment in original {
+        let lost = balance[comment.as_str()].max(0);
+        let n = unmatched.entry(comment).or_default();
+        if *n > lost {
+            *n -= 1;
+            check.moved.push(comment.clone());
+        }
+    }
+
+    check
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(comments: &[&str]) -> Vec<String> {
+        comments.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn hash_inside_literals_is_not_a_comment() {
+        let source = r##"a = "x # y" # after string
+b = '#' # after char
+c = "$(Str.concat "#" d) # still in the string" # after interpolation
+"##;
+        assert_eq!(
+            comments(source),
+            ["# after string", "# after char", "# after interpolation"]
+        );
+    }
+
+    #[test]
+    fn triple_quote_outside_a_block_string() {
+        let source = r##"# """ in a comment
+a = "escaped \"\"\" # in a plain string" # after plain string
+b =
+    """
+    # in a block string
+    """ # after block string
+"##;
+        assert_eq!(
+            comments(source),
+            [
+                r#"# """ in a comment"#,
+                "# after plain string",
+                "# after block string"
+            ]
+        );
+    }
+
+    #[test]
+    fn lost_duplicated_and_moved() {
+        let check = check_comments(&strings(&["# a", "# b"]), &strings(&["# a"]));
+        assert_eq!(check.lost, ["# b"]);
+        assert!(check.duplicated.is_empty() && check.moved.is_empty());
+
+        let check = check_comments(&strings(&["# a"]), &strings(&["# a", "# a"]));
+        assert_eq!(check.duplicated, ["# a"]);
+        assert!(check.lost.is_empty() && check.moved.is_empty());
+
+        let check = check_comments(
+            &strings(&["# a", "# b", "# c"]),
+            &strings(&["# b", "# c", "# a"]),
+        );
+        assert_eq!(check.moved, ["# a"]);
+        assert!(check.lost.is_empty() && check.duplicated.is_empty());
+
+        let same = strings(&["# a", "## b"]);
+        assert!(check_comments(&same, &same).preserved());
+    }
+}
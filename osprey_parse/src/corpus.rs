@@ -0,0 +1,378 @@
+use flate2::read::GzDecoder;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rusqlite::{params, Connection, Result, ToSql};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use structopt::StructOpt;
+
+// Where the files come from. Directories, tarballs and fuzzer artifacts are
+// loaded into an in-memory `roc_files` table, so everything downstream,
+// filters included, works the same for all of them.
+#[derive(StructOpt, Debug, Clone)]
+pub struct CorpusSource {
+    /// The crawler's sqlite db
+    #[structopt(short, long, required_unless_one = &["corpus-dir", "corpus-tar", "corpus-fuzz-artifacts"])]
+    corpus_db: Option<String>,
+    /// A directory of checkouts laid out as `<host>/<owner>/<repo>/<path>`,
+    /// or as `<owner>/<repo>/<path>` for GitHub repos
+    #[structopt(long, conflicts_with_all = &["corpus-db", "corpus-tar", "corpus-fuzz-artifacts"])]
+    corpus_dir: Option<PathBuf>,
+    /// A `.tar.gz` from the web viewer's `/tarball` route
+    #[structopt(long, conflicts_with_all = &["corpus-db", "corpus-dir", "corpus-fuzz-artifacts"])]
+    corpus_tar: Option<PathBuf>,
+    /// cargo fuzz's `artifacts` directory, or one target's directory in it.
+    /// Every crash, timeout and oom input is a file of repo `fuzz/<target>`.
+    #[structopt(long, conflicts_with_all = &["corpus-db", "corpus-dir", "corpus-tar"])]
+    corpus_fuzz_artifacts: Option<PathBuf>,
+}
+
+impl CorpusSource {
+    // For recording with the run.
+    pub fn describe(&self) -> String {
+        match (
+            &self.corpus_db,
+            &self.corpus_dir,
+            &self.corpus_tar,
+            &self.corpus_fuzz_artifacts,
+        ) {
+            (Some(db), _, _, _) => db.clone(),
+            (_, Some(dir), _, _) => format!("dir:{}", dir.display()),
+            (_, _, Some(tar), _) => format!("tar:{}", tar.display()),
+            (_, _, _, Some(artifacts)) => format!("fuzz:{}", artifacts.display()),
+            _ => unreachable!("structopt requires one corpus source"),
+        }
+    }
+
+    // Fuzzer artifacts are inputs that crashed, hung or ran out of memory, so
+    // they're only ever parsed in worker processes.
+    pub fn is_fuzz_artifacts(&self) -> bool {
+        self.corpus_fuzz_artifacts.is_some()
+    }
+
+    pub fn open(&self) -> Result<Connection> {
+        if let Some(db) = &self.corpus_db {
+            return Connection::open(db);
+        }
+
+        let mut files = Vec::new();
+        let read = match (
+            &self.corpus_dir,
+            &self.corpus_tar,
+            &self.corpus_fuzz_artifacts,
+        ) {
+            (Some(dir), _, _) => read_dir(dir, dir, &mut files),
+            (_, Some(tar), _) => read_tar(tar, &mut files),
+            (_, _, Some(artifacts)) => read_artifacts(artifacts, &mut files),
+            _ => unreachable!("structopt requires one corpus source"),
+        };
+        if let Err(e) = read {
+            eprintln!("Failed to read corpus {}: {}", self.describe(), e);
+            std::process::exit(1);
+        }
+        files.sort();
+
+        let mut conn = Connection::open_in_memory()?;
+        conn.execute(
+            "CREATE TABLE roc_files (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                file_hash TEXT,
+                retrieval_date TEXT,
+                file_contents TEXT,
+                repo_url TEXT,
+                file_path TEXT
+            )",
+            [],
+        )?;
+        let transaction = conn.transaction()?;
+        let mut skipped = 0;
+        let split = if self.is_fuzz_artifacts() {
+            split_artifact
+        } else {
+            split_repo
+        };
+        for (path, contents) in files {
+            let Some((repo_url, file_path)) = split(&path) else {
+                skipped += 1;
+                continue;
+            };
+            // Hashed like the crawler does, so `--incremental` can reuse
+            // results from runs over the sqlite corpus.
+            let file_hash = format!("{:x}", Sha256::digest(contents.as_bytes()));
+            transaction.execute(
+                "INSERT INTO roc_files (file_hash, file_contents, repo_url, file_path)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![file_hash, contents, repo_url, file_path],
+            )?;
+        }
+        transaction.commit()?;
+        if skipped > 0 {
+            println!("Skipped {} files not inside a repo directory", skipped);
+        }
+        Ok(conn)
+    }
+}
+
+// `.roc` files under `dir` as (path relative to `root`, contents). Hidden
+// directories are skipped, which keeps `.git` and editor state out.
+fn read_dir(root: &Path, dir: &Path, files: &mut Vec<(String, String)>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        {
+            continue;
+        }
+        if path.is_dir() {
+            read_dir(root, &path, files)?;
+        } else if is_roc(&path) {
+            let Ok(contents) = fs::read_to_string(&path) else {
+                eprintln!("Skipping non-UTF-8 file {}", path.display());
+                continue;
+            };
+            let relative = path.strip_prefix(root).unwrap();
+            files.push((slash_path(relative), contents));
+        }
+    }
+    Ok(())
+}
+
+// Every file under `<target>/` in cargo fuzz's `artifacts` directory, as
+// `<target>/<name>`. Given one target's directory, its name stands in for the
+// `<target>/`. The fuzz targets skip input that isn't UTF-8, so none of the
+// artifacts should be either.
+fn read_artifacts(root: &Path, files: &mut Vec<(String, String)>) -> io::Result<()> {
+    let mut found = Vec::new();
+    read_all(root, &mut found)?;
+    for path in found {
+        let mut relative = slash_path(path.strip_prefix(root).unwrap());
+        if !relative.contains('/') {
+            let target = root.file_name().unwrap_or_default().to_string_lossy();
+            relative = format!("{}/{}", target, relative);
+        }
+        let contents = String::from_utf8_lossy(&fs::read(&path)?).into_owned();
+        files.push((relative, contents));
+    }
+    Ok(())
+}
+
+fn read_all(dir: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        {
+            continue;
+        }
+        if path.is_dir() {
+            read_all(&path, found)?;
+        } else {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn read_tar(tar: &Path, files: &mut Vec<(String, String)>) -> io::Result<()> {
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(tar)?));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        if !is_roc(&path) {
+            continue;
+        }
+        let mut contents = String::new();
+        if entry.read_to_string(&mut contents).is_err() {
+            eprintln!("Skipping non-UTF-8 file {}", path.display());
+            continue;
+        }
+        files.push((slash_path(&path), contents));
+    }
+    Ok(())
+}
+
+fn is_roc(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "roc")
+}
+
+fn slash_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// The tarball names each file `repo_url[len("https://"):] + "/" + file_path`,
+// so a first component with a dot in it is a host.
+fn split_repo(path: &str) -> Option<(String, String)> {
+    let parts: Vec<&str> = path.splitn(4, '/').collect();
+    match parts.as_slice() {
+        [host, owner, repo, file_path] if host.contains('.') => Some((
+            format!("https://{}/{}/{}", host, owner, repo),
+            file_path.to_string(),
+        )),
+        [owner, repo, file_path @ ..] if !file_path.is_empty() && !owner.contains('.') => Some((
+            format!("https://github.com/{}/{}", owner, repo),
+            file_path.join("/"),
+        )),
+        _ => None,
+    }
+}
+
+fn split_artifact(path: &str) -> Option<(String, String)> {
+    let (target, name) = path.split_once('/')?;
+    Some((format!("fuzz/{}", target), name.to_string()))
+}
+
+// Which rows of `roc_files` a command looks at. Shared by `parse` and `diff`,
+// so that diffing with the same filters and seed compares the same files.
+#[derive(StructOpt, Debug, Clone)]
+pub struct CorpusFilter {
+    /// Only repos whose url matches this glob, e.g. `*/roc-lang/*` (repeatable)
+    #[structopt(long = "repo-glob")]
+    repo_globs: Vec<String>,
+    /// Only files whose path matches this glob, e.g. `*/Test*.roc` (repeatable)
+    #[structopt(long = "path-glob")]
+    path_globs: Vec<String>,
+    /// Skip repos whose url matches this glob; the default leaves out the
+    /// compiler's own repo, pass '' to keep it (repeatable)
+    #[structopt(long = "exclude-repo-glob", default_value = "*/roc")]
+    exclude_repo_globs: Vec<String>,
+    /// Also take files hidden in the web viewer
+    #[structopt(long)]
+    include_hidden: bool,
+    /// Take at most this many files
+    #[structopt(long)]
+    limit: Option<usize>,
+    /// How to pick the --limit files: `first`, `random`, or `per-repo` for a
+    /// random sample spread evenly over repos
+    #[structopt(long, default_value = "first", possible_values = &["first", "random", "per-repo"])]
+    sample: String,
+    /// Seed for --sample random and per-repo
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+}
+
+pub struct CorpusEntry {
+    pub id: i64,
+    pub repo_url: String,
+    pub file_path: String,
+    pub retrieval_date: Option<String>,
+}
+
+impl CorpusFilter {
+    // Selected files in `roc_files` order, whatever the sampling.
+    pub fn select(&self, conn: &Connection) -> Result<Vec<CorpusEntry>> {
+        let mut conditions = Vec::new();
+        let mut params: Vec<&dyn ToSql> = Vec::new();
+
+        for (column, globs, negate) in [
+            ("repo_url", &self.repo_globs, false),
+            ("file_path", &self.path_globs, false),
+            ("repo_url", &self.exclude_repo_globs, true),
+        ] {
+            if globs.is_empty() {
+                continue;
+            }
+            let any = vec![format!("{} GLOB ?", column); globs.len()].join(" OR ");
+            conditions.push(if negate {
+                format!("NOT ({})", any)
+            } else {
+                format!("({})", any)
+            });
+            params.extend(globs.iter().map(|glob| glob as &dyn ToSql));
+        }
+
+        // Older corpus dbs predate the web viewer's `hidden` column.
+        if !self.include_hidden && has_column(conn, "roc_files", "hidden")? {
+            conditions.push("hidden = 0".to_string());
+        }
+
+        let mut sql = "SELECT id, repo_url, file_path, retrieval_date FROM roc_files".to_string();
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY id");
+
+        let mut stmt = conn.prepare(&sql)?;
+        let entries = stmt
+            .query_map(params.as_slice(), |row| {
+                Ok(CorpusEntry {
+                    id: row.get(0)?,
+                    repo_url: row.get(1)?,
+                    file_path: row.get(2)?,
+                    retrieval_date: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(self.sample(entries))
+    }
+
+    fn sample(&self, mut entries: Vec<CorpusEntry>) -> Vec<CorpusEntry> {
+        let Some(limit) = self.limit else {
+            return entries;
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+
+        match self.sample.as_str() {
+            "random" => {
+                entries.shuffle(&mut rng);
+                entries.truncate(limit);
+            }
+            "per-repo" => {
+                let mut by_repo: BTreeMap<String, Vec<CorpusEntry>> = BTreeMap::new();
+                for entry in entries {
+                    by_repo
+                        .entry(entry.repo_url.clone())
+                        .or_default()
+                        .push(entry);
+                }
+                let mut repos: Vec<Vec<CorpusEntry>> = by_repo.into_values().collect();
+                repos.shuffle(&mut rng);
+                for files in &mut repos {
+                    files.shuffle(&mut rng);
+                }
+
+                // Deal one file per repo at a time, so small repos are as
+                // likely to be represented as big ones.
+                let mut sampled = Vec::new();
+                while sampled.len() < limit && !repos.is_empty() {
+                    repos.retain_mut(|files| match files.pop() {
+                        Some(entry) if sampled.len() < limit => {
+                            sampled.push(entry);
+                            true
+                        }
+                        _ => false,
+                    });
+                }
+                entries = sampled;
+            }
+            _ => entries.truncate(limit),
+        }
+
+        entries.sort_by_key(|entry| entry.id);
+        entries
+    }
+}
+
+pub fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(columns.iter().any(|c| c == column))
+}
@@ -0,0 +1,617 @@
+use rusqlite::OptionalExtension;
+use rusqlite::{params, Connection, Result};
+use std::collections::HashMap;
+
+use crate::defs::DefResult;
+use crate::{CorpusFile, ParseData, PARSER_BUILD_ID, ROC_REVISION};
+
+// Columns that were added after the original schema. Results dbs created by
+// older builds get them via ALTER TABLE so they can still be diffed.
+const ADDED_RESULT_COLUMNS: &[(&str, &str)] = &[
+    ("panic_stage", "TEXT"),
+    ("panic_message", "TEXT"),
+    ("panic_backtrace", "TEXT"),
+    ("outcome", "TEXT"),
+    ("outcome_details", "TEXT"),
+    ("file_hash", "TEXT"),
+    ("parser_build_id", "TEXT"),
+    ("run_id", "INTEGER REFERENCES runs(id)"),
+    ("error_kind", "TEXT"),
+    ("error_offset", "INTEGER"),
+    ("error_line", "INTEGER"),
+    ("error_column", "INTEGER"),
+    ("error_source_line", "TEXT"),
+    ("reparse_error_kind", "TEXT"),
+    ("reparse_error_offset", "INTEGER"),
+    ("reparse_error_line", "INTEGER"),
+    ("reparse_error_column", "INTEGER"),
+    ("reparse_error_source_line", "TEXT"),
+    ("entrypoint", "TEXT"),
+    ("results_version", "INTEGER"),
+    ("parse_us", "INTEGER"),
+    ("format_us", "INTEGER"),
+    ("reparse_us", "INTEGER"),
+    ("normalize_us", "INTEGER"),
+    ("double_format_us", "INTEGER"),
+    ("parse_bytes_per_sec", "REAL"),
+    ("arena_bytes_parse", "INTEGER"),
+    ("arena_bytes_format", "INTEGER"),
+    ("arena_bytes_reparse", "INTEGER"),
+    ("duplicate_of", "INTEGER REFERENCES roc_parse_results(id)"),
+    ("divergence_path", "TEXT"),
+    ("divergence_kind", "TEXT"),
+    ("divergence_start", "INTEGER"),
+    ("divergence_end", "INTEGER"),
+    ("divergence_fmt_start", "INTEGER"),
+    ("divergence_fmt_end", "INTEGER"),
+    ("comments_preserved", "BOOL"),
+    ("comments_lost", "TEXT"),
+    ("comments_duplicated", "TEXT"),
+    ("comments_moved", "TEXT"),
+    ("fmt_hygienic", "BOOL"),
+    ("fmt_hygiene_violations", "TEXT"),
+    ("fmt_convergence", "TEXT"),
+    ("fmt_passes_to_converge", "INTEGER"),
+    ("fmt_cycle_length", "INTEGER"),
+    ("fmt_pass_sizes", "TEXT"),
+    ("fmt_grows", "BOOL"),
+];
+
+// Bumped whenever `parse_one` starts recording something new or recording it
+// differently, so that `parse --incremental` doesn't copy rows that lack it.
+// The parser build id can't catch this, since it only covers roc_parse and
+// roc_fmt.
+const RESULTS_VERSION: i64 = 9;
+
+// Every column that `parse_one` fills in, i.e. everything that can be copied
+// from an earlier row with the same file hash and parser build.
+const RESULT_DATA_COLUMNS: &str = "output, error, fmt_output, reparse_output, reparse_error,
+    normalized_output, normalized_reparse_output, double_fmt_output,
+    fmt_changed, fmt_changed_syntax, fmt_idempotent,
+    panic_stage, panic_message, panic_backtrace,
+    outcome, outcome_details,
+    error_kind, error_offset, error_line, error_column, error_source_line,
+    reparse_error_kind, reparse_error_offset, reparse_error_line,
+    reparse_error_column, reparse_error_source_line, entrypoint,
+    parse_us, format_us, reparse_us, normalize_us, double_format_us, parse_bytes_per_sec,
+    arena_bytes_parse, arena_bytes_format, arena_bytes_reparse,
+    divergence_path, divergence_kind, divergence_start, divergence_end,
+    divergence_fmt_start, divergence_fmt_end,
+    comments_preserved, comments_lost, comments_duplicated, comments_moved,
+    fmt_hygienic, fmt_hygiene_violations,
+    fmt_convergence, fmt_passes_to_converge, fmt_cycle_length, fmt_pass_sizes, fmt_grows";
+
+pub fn ensure_results_schema(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS runs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT UNIQUE,
+            started_at TEXT,
+            finished_at TEXT,
+            roc_revision TEXT,
+            parser_build_id TEXT,
+            command_line TEXT,
+            corpus_db TEXT,
+            corpus_files INTEGER,
+            corpus_retrieved_at TEXT
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS roc_parse_results (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            repo_url TEXT,
+            file_path TEXT,
+            contents TEXT,
+            output TEXT,
+            error TEXT,
+            fmt_output TEXT,
+            reparse_output TEXT,
+            reparse_error TEXT,
+            normalized_output TEXT,
+            normalized_reparse_output TEXT,
+            double_fmt_output TEXT,
+            fmt_changed TEXT,
+            fmt_changed_syntax BOOL,
+            fmt_idempotent BOOL
+        )",
+        [],
+    )?;
+
+    let mut stmt = conn.prepare("PRAGMA table_info(roc_parse_results)")?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>>>()?;
+
+    for (name, ty) in ADDED_RESULT_COLUMNS {
+        if !columns.iter().any(|c| c == name) {
+            conn.execute(
+                &format!("ALTER TABLE roc_parse_results ADD COLUMN {} {}", name, ty),
+                [],
+            )?;
+        }
+    }
+
+    // Results written before runs existed all belong to one anonymous run.
+    let has_legacy_rows = conn
+        .query_row(
+            "SELECT 1 FROM roc_parse_results WHERE run_id IS NULL LIMIT 1",
+            [],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if has_legacy_rows {
+        conn.execute("INSERT INTO runs (name) VALUES ('legacy')", [])?;
+        conn.execute(
+            "UPDATE roc_parse_results SET run_id = ?1 WHERE run_id IS NULL",
+            [conn.last_insert_rowid()],
+        )?;
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS def_results (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            result_id INTEGER REFERENCES roc_parse_results(id),
+            def_index INTEGER,
+            name TEXT,
+            region_start INTEGER,
+            region_end INTEGER,
+            error_kind TEXT,
+            reparse_error_kind TEXT,
+            fmt_changed_syntax BOOL,
+            fmt_idempotent BOOL,
+            panic_message TEXT,
+            fmt_output TEXT
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_def_results_result ON def_results (result_id)",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_results_path
+         ON roc_parse_results (run_id, repo_url, file_path)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_results_hash
+         ON roc_parse_results (file_hash, parser_build_id)",
+        [],
+    )?;
+
+    Ok(())
+}
+
+pub struct RunInfo {
+    pub name: Option<String>,
+    pub command_line: String,
+    pub corpus_db: String,
+    pub corpus_files: i64,
+    pub corpus_retrieved_at: Option<String>,
+}
+
+pub fn create_run(conn: &Connection, info: &RunInfo) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO runs (
+            name, started_at, roc_revision, parser_build_id, command_line,
+            corpus_db, corpus_files, corpus_retrieved_at
+        ) VALUES (?1, datetime('now'), ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            info.name,
+            ROC_REVISION,
+            PARSER_BUILD_ID,
+            info.command_line,
+            info.corpus_db,
+            info.corpus_files,
+            info.corpus_retrieved_at
+        ],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn finish_run(conn: &Connection, run_id: i64) -> Result<()> {
+    conn.execute(
+        "UPDATE runs SET finished_at = datetime('now') WHERE id = ?1",
+        [run_id],
+    )?;
+    Ok(())
+}
+
+// A run can be named by its name or its numeric id; without either, the most
+// recent run is used, which is all there is in a single-run results db.
+pub fn resolve_run(conn: &Connection, run: Option<&str>) -> Result<Option<i64>> {
+    match run {
+        None => conn.query_row("SELECT max(id) FROM runs", [], |row| row.get(0)),
+        Some(run) => conn
+            .query_row(
+                "SELECT id FROM runs WHERE name = ?1 OR CAST(id AS TEXT) = ?1
+                 ORDER BY name = ?1 DESC
+                 LIMIT 1",
+                [run],
+                |row| row.get(0),
+            )
+            .optional(),
+    }
+}
+
+// The latest run started before `run_id`, which is what a run is usually
+// compared against.
+pub fn previous_run(conn: &Connection, run_id: i64) -> Result<Option<i64>> {
+    conn.query_row("SELECT max(id) FROM runs WHERE id < ?1", [run_id], |row| {
+        row.get(0)
+    })
+}
+
+pub fn load_result(
+    conn: &Connection,
+    run_id: i64,
+    repo_url: &str,
+    file_path: &str,
+) -> Result<Option<ParseData>> {
+    query_result(
+        conn,
+        "run_id = ?1 and repo_url = ?2 and file_path = ?3",
+        params![run_id, repo_url, file_path],
+    )
+}
+
+// A path can have several rows in one run (a results db written to by more
+// than one `parse`), so commands that take `--id` load exactly that row.
+pub fn load_result_by_id(conn: &Connection, id: i64) -> Result<Option<ParseData>> {
+    query_result(conn, "id = ?1", [id])
+}
+
+fn query_result(
+    conn: &Connection,
+    condition: &str,
+    params: impl rusqlite::Params,
+) -> Result<Option<ParseData>> {
+    let found = conn
+        .query_row(
+            &format!(
+                "SELECT contents, output, error, fmt_output, reparse_output, reparse_error,
+            normalized_output, normalized_reparse_output, double_fmt_output,
+            fmt_changed, fmt_changed_syntax, fmt_idempotent,
+            panic_stage, panic_message, panic_backtrace,
+            outcome, outcome_details,
+            error_kind, error_offset, error_line, error_column, error_source_line,
+            reparse_error_kind, reparse_error_offset, reparse_error_line,
+            reparse_error_column, reparse_error_source_line, entrypoint,
+            parse_us, format_us, reparse_us, normalize_us, double_format_us,
+            parse_bytes_per_sec, arena_bytes_parse, arena_bytes_format, arena_bytes_reparse,
+            divergence_path, divergence_kind, divergence_start, divergence_end,
+            divergence_fmt_start, divergence_fmt_end,
+            comments_preserved, comments_lost, comments_duplicated, comments_moved,
+            fmt_hygienic, fmt_hygiene_violations,
+            fmt_convergence, fmt_passes_to_converge, fmt_cycle_length, fmt_pass_sizes, fmt_grows,
+            id
+         FROM roc_parse_results
+         WHERE {}
+         ORDER BY id DESC
+         LIMIT 1",
+                condition
+            ),
+            params,
+            |row| {
+                let data = ParseData {
+                    output: row.get(1)?,
+                    error: row.get(2)?,
+                    fmt_output: row.get(3)?,
+                    reparse_output: row.get(4)?,
+                    reparse_error: row.get(5)?,
+                    normalized_output: row.get(6)?,
+                    normalized_reparse_output: row.get(7)?,
+                    double_fmt_output: row.get(8)?,
+                    fmt_changed: row.get(9)?,
+                    fmt_changed_syntax: row.get(10)?,
+                    fmt_idempotent: row.get(11)?,
+                    panic_stage: row.get(12)?,
+                    panic_message: row.get(13)?,
+                    panic_backtrace: row.get(14)?,
+                    outcome: row.get(15)?,
+                    outcome_details: row.get(16)?,
+                    error_kind: row.get(17)?,
+                    error_offset: row.get(18)?,
+                    error_line: row.get(19)?,
+                    error_column: row.get(20)?,
+                    error_source_line: row.get(21)?,
+                    reparse_error_kind: row.get(22)?,
+                    reparse_error_offset: row.get(23)?,
+                    reparse_error_line: row.get(24)?,
+                    reparse_error_column: row.get(25)?,
+                    reparse_error_source_line: row.get(26)?,
+                    entrypoint: row.get(27)?,
+                    parse_us: row.get(28)?,
+                    format_us: row.get(29)?,
+                    reparse_us: row.get(30)?,
+                    normalize_us: row.get(31)?,
+                    double_format_us: row.get(32)?,
+                    parse_bytes_per_sec: row.get(33)?,
+                    arena_bytes_parse: row.get(34)?,
+                    arena_bytes_format: row.get(35)?,
+                    arena_bytes_reparse: row.get(36)?,
+                    divergence_path: row.get(37)?,
+                    divergence_kind: row.get(38)?,
+                    divergence_start: row.get(39)?,
+                    divergence_end: row.get(40)?,
+                    divergence_fmt_start: row.get(41)?,
+                    divergence_fmt_end: row.get(42)?,
+                    comments_preserved: row.get(43)?,
+                    comments_lost: row.get(44)?,
+                    comments_duplicated: row.get(45)?,
+                    comments_moved: row.get(46)?,
+                    fmt_hygienic: row.get(47)?,
+                    fmt_hygiene_violations: row.get(48)?,
+                    fmt_convergence: row.get(49)?,
+                    fmt_passes_to_converge: row.get(50)?,
+                    fmt_cycle_length: row.get(51)?,
+                    fmt_pass_sizes: row.get(52)?,
+                    fmt_grows: row.get(53)?,
+                    def_results: Vec::new(),
+                };
+                Ok((row.get::<_, i64>(54)?, data))
+            },
+        )
+        .optional()?;
+
+    let Some((id, mut data)) = found else {
+        return Ok(None);
+    };
+    data.def_results = load_def_results(conn, id)?;
+    Ok(Some(data))
+}
+
+fn load_def_results(conn: &Connection, result_id: i64) -> Result<Vec<DefResult>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM def_results WHERE result_id = ?1 ORDER BY id",
+        DEF_RESULT_DATA_COLUMNS
+    ))?;
+    let rows = stmt.query_map([result_id], |row| {
+        Ok(DefResult {
+            index: row.get(0)?,
+            name: row.get(1)?,
+            start: row.get(2)?,
+            end: row.get(3)?,
+            error_kind: row.get(4)?,
+            reparse_error_kind: row.get(5)?,
+            fmt_changed_syntax: row.get(6)?,
+            fmt_idempotent: row.get(7)?,
+            panic_message: row.get(8)?,
+            fmt_output: row.get(9)?,
+        })
+    })?;
+    rows.collect()
+}
+
+// The source a results row was produced from, by row id or by path within a run.
+pub fn load_contents_by_id(conn: &Connection, id: i64) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT contents FROM roc_parse_results WHERE id = ?1",
+        [id],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+// The run, repo and path of a results row, for commands that take `--id`.
+pub fn load_row_key(conn: &Connection, id: i64) -> Result<Option<(i64, String, String)>> {
+    conn.query_row(
+        "SELECT run_id, repo_url, file_path FROM roc_parse_results WHERE id = ?1",
+        [id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )
+    .optional()
+}
+
+pub fn load_contents(
+    conn: &Connection,
+    run_id: i64,
+    repo_url: &str,
+    file_path: &str,
+) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT contents FROM roc_parse_results
+         WHERE run_id = ?1 and repo_url = ?2 and file_path = ?3
+         ORDER BY id DESC
+         LIMIT 1",
+        params![run_id, repo_url, file_path],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+// Returns the new row's id.
+pub fn insert_result(
+    conn: &Connection,
+    run_id: i64,
+    file: &CorpusFile,
+    result: &ParseData,
+) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO roc_parse_results (
+            repo_url, file_path,
+            contents, output, error, fmt_output, reparse_output, reparse_error,
+            normalized_output, normalized_reparse_output, double_fmt_output,
+            fmt_changed, fmt_changed_syntax, fmt_idempotent,
+            panic_stage, panic_message, panic_backtrace,
+            outcome, outcome_details,
+            file_hash, parser_build_id, run_id,
+            error_kind, error_offset, error_line, error_column, error_source_line,
+            reparse_error_kind, reparse_error_offset, reparse_error_line,
+            reparse_error_column, reparse_error_source_line, entrypoint,
+            results_version,
+            parse_us, format_us, reparse_us, normalize_us, double_format_us,
+            parse_bytes_per_sec, arena_bytes_parse, arena_bytes_format, arena_bytes_reparse,
+            divergence_path, divergence_kind, divergence_start, divergence_end,
+            divergence_fmt_start, divergence_fmt_end,
+            comments_preserved, comments_lost, comments_duplicated, comments_moved,
+            fmt_hygienic, fmt_hygiene_violations,
+            fmt_convergence, fmt_passes_to_converge, fmt_cycle_length, fmt_pass_sizes, fmt_grows
+        ) VALUES (
+            ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10,
+            ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20,
+            ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30,
+            ?31, ?32, ?33, ?34, ?35, ?36, ?37, ?38, ?39, ?40,
+            ?41, ?42, ?43, ?44, ?45, ?46, ?47, ?48, ?49, ?50,
+            ?51, ?52, ?53, ?54, ?55, ?56, ?57, ?58, ?59, ?60
+        )",
+        params![
+            file.repo_url,
+            file.file_path,
+            file.contents,
+            result.output,
+            result.error,
+            result.fmt_output,
+            result.reparse_output,
+            result.reparse_error,
+            result.normalized_output,
+            result.normalized_reparse_output,
+            result.double_fmt_output,
+            result.fmt_changed,
+            result.fmt_changed_syntax,
+            result.fmt_idempotent,
+            result.panic_stage,
+            result.panic_message,
+            result.panic_backtrace,
+            result.outcome,
+            result.outcome_details,
+            file.file_hash,
+            PARSER_BUILD_ID,
+            run_id,
+            result.error_kind,
+            result.error_offset,
+            result.error_line,
+            result.error_column,
+            result.error_source_line,
+            result.reparse_error_kind,
+            result.reparse_error_offset,
+            result.reparse_error_line,
+            result.reparse_error_column,
+            result.reparse_error_source_line,
+            result.entrypoint,
+            RESULTS_VERSION,
+            result.parse_us,
+            result.format_us,
+            result.reparse_us,
+            result.normalize_us,
+            result.double_format_us,
+            result.parse_bytes_per_sec,
+            result.arena_bytes_parse,
+            result.arena_bytes_format,
+            result.arena_bytes_reparse,
+            result.divergence_path,
+            result.divergence_kind,
+            result.divergence_start,
+            result.divergence_end,
+            result.divergence_fmt_start,
+            result.divergence_fmt_end,
+            result.comments_preserved,
+            result.comments_lost,
+            result.comments_duplicated,
+            result.comments_moved,
+            result.fmt_hygienic,
+            result.fmt_hygiene_violations,
+            result.fmt_convergence,
+            result.fmt_passes_to_converge,
+            result.fmt_cycle_length,
+            result.fmt_pass_sizes,
+            result.fmt_grows
+        ],
+    )?;
+    let id = conn.last_insert_rowid();
+
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO def_results (
+            result_id, def_index, name, region_start, region_end,
+            error_kind, reparse_error_kind, fmt_changed_syntax, fmt_idempotent,
+            panic_message, fmt_output
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+    )?;
+    for def in &result.def_results {
+        stmt.execute(params![
+            id,
+            def.index,
+            def.name,
+            def.start,
+            def.end,
+            def.error_kind,
+            def.reparse_error_kind,
+            def.fmt_changed_syntax,
+            def.fmt_idempotent,
+            def.panic_message,
+            def.fmt_output
+        ])?;
+    }
+    Ok(id)
+}
+
+const DEF_RESULT_DATA_COLUMNS: &str = "def_index, name, region_start, region_end,
+    error_kind, reparse_error_kind, fmt_changed_syntax, fmt_idempotent,
+    panic_message, fmt_output";
+
+// Copies the results of row `from_id` for `file`, def results included.
+// `duplicate_of` marks rows whose contents already appear earlier in the same
+// run, so that summaries can count unique contents. Returns the new row's id.
+pub fn copy_result(
+    conn: &Connection,
+    run_id: i64,
+    file: &CorpusFile,
+    from_id: i64,
+    duplicate_of: Option<i64>,
+) -> Result<i64> {
+    conn.execute(
+        &format!(
+            "INSERT INTO roc_parse_results (
+                repo_url, file_path, contents, file_hash, parser_build_id, run_id,
+                duplicate_of, results_version, {columns}
+            )
+            SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, results_version, {columns}
+            FROM roc_parse_results WHERE id = ?8",
+            columns = RESULT_DATA_COLUMNS
+        ),
+        params![
+            file.repo_url,
+            file.file_path,
+            file.contents,
+            file.file_hash,
+            PARSER_BUILD_ID,
+            run_id,
+            duplicate_of,
+            from_id
+        ],
+    )?;
+    let id = conn.last_insert_rowid();
+
+    conn.execute(
+        &format!(
+            "INSERT INTO def_results (result_id, {columns})
+             SELECT ?1, {columns} FROM def_results WHERE result_id = ?2 ORDER BY id",
+            columns = DEF_RESULT_DATA_COLUMNS
+        ),
+        params![id, from_id],
+    )?;
+    Ok(id)
+}
+
+// Results from earlier runs with this parser build, for `parse --incremental`:
+// file_hash -> id of a row whose results can be copied into the new run.
+// Timeouts, ooms and crashes depend on the limits that run was given rather
+// than on the contents, and so does how far a file that isn't idempotent got
+// with `--max-fmt-passes`, so those files are always parsed again.
+pub fn load_completed_results(conn: &Connection) -> Result<HashMap<String, i64>> {
+    let mut stmt = conn.prepare(
+        "SELECT file_hash, max(id) FROM roc_parse_results
+         WHERE parser_build_id = ?1 AND results_version = ?2 AND file_hash IS NOT NULL
+           AND coalesce(outcome, 'completed') = 'completed'
+           AND coalesce(fmt_idempotent, 1) = 1
+         GROUP BY file_hash",
+    )?;
+    let rows = stmt.query_map(params![PARSER_BUILD_ID, RESULTS_VERSION], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+    })?;
+    rows.collect()
+}
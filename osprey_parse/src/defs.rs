@@ -0,0 +1,100 @@
+// Per-def results. A file gets a single verdict, so one formatter bug in a
+// file with hundreds of defs makes the whole file look broken. Here each
+// top-level def's source is cut out by its region and sent through the same
+// format/reparse/compare steps on its own.
+
+use bumpalo::Bump;
+use roc_parse::normalize::Normalize;
+use serde::{Deserialize, Serialize};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::{format_parsed, panic_message, parse_with, take_panic_backtrace, Entrypoint, Parsed};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DefResult {
+    pub index: usize,
+    pub name: String,
+    // Byte range in the file
+    pub start: usize,
+    pub end: usize,
+    pub error_kind: Option<String>,
+    pub reparse_error_kind: Option<String>,
+    pub fmt_changed_syntax: Option<bool>,
+    pub fmt_idempotent: Option<bool>,
+    pub panic_message: Option<String>,
+    // Only kept when one of the checks failed
+    pub fmt_output: Option<String>,
+}
+
+pub fn check_defs(input: &str, parsed: &Parsed) -> Vec<DefResult> {
+    let defs = match parsed {
+        Parsed::Module(output) => &output.module_defs,
+        Parsed::Defs(defs) => defs,
+        Parsed::Expr(_) => return Vec::new(),
+    };
+
+    defs.regions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, region)| {
+            let (start, end) = (region.start().offset as usize, region.end().offset as usize);
+            let text = input.get(start..end)?;
+            let mut result = DefResult {
+                index,
+                name: def_name(text),
+                start,
+                end,
+                ..DefResult::default()
+            };
+            // A panic is recorded against the def and the rest carry on. Its
+            // backtrace isn't kept, and mustn't be left behind for
+            // `parse_one` to take for one of the file's own.
+            let checked = panic::catch_unwind(AssertUnwindSafe(|| check_def(text, &mut result)));
+            take_panic_backtrace();
+            if let Err(payload) = checked {
+                result.panic_message = Some(panic_message(&*payload));
+            }
+            Some(result)
+        })
+        .collect()
+}
+
+// Top-level defs start in column 0, so the cut out text parses as defs. Each
+// gets its own arena so a big file's defs don't pile up in one.
+fn check_def(text: &str, result: &mut DefResult) {
+    let arena = Bump::new();
+    let parsed = match parse_with(Entrypoint::Defs, text, &arena) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            result.error_kind = Some(e.kind);
+            return;
+        }
+    };
+    let formatted = format_parsed(&parsed, &arena);
+
+    let reparsed = match parse_with(Entrypoint::Defs, &formatted, &arena) {
+        Ok(reparsed) => reparsed,
+        Err(e) => {
+            result.reparse_error_kind = Some(e.kind);
+            result.fmt_output = Some(formatted);
+            return;
+        }
+    };
+    let changed_syntax = format!("{:#?}", parsed.remove_spaces(&arena))
+        != format!("{:#?}", reparsed.remove_spaces(&arena));
+    let idempotent = format_parsed(&reparsed, &arena) == formatted;
+
+    result.fmt_changed_syntax = Some(changed_syntax);
+    result.fmt_idempotent = Some(idempotent);
+    if changed_syntax || !idempotent {
+        result.fmt_output = Some(formatted);
+    }
+}
+
+// What the def binds, as written: `main`, `Foo a`, `import pf.Stdout`. Good
+// enough to find it again, without matching on every kind of def.
+fn def_name(text: &str) -> String {
+    let first_line = text.lines().next().unwrap_or("");
+    let end = first_line.find([':', '=']).unwrap_or(first_line.len());
+    first_line[..end].trim().to_string()
+}
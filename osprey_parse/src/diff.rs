@@ -0,0 +1,651 @@
+use rusqlite::{Connection, Result};
+use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::ast_diff::{side_by_side, Divergence};
+use crate::corpus::CorpusEntry;
+use crate::db::{load_contents, load_result};
+use crate::worker::OUTCOME_COMPLETED;
+use crate::{Entrypoint, ParseData};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown format {:?} (expected text or json)", s)),
+        }
+    }
+}
+
+pub struct DiffOptions {
+    pub format: OutputFormat,
+    // Render unified diffs of the text fields that changed
+    pub show_diff: bool,
+    pub context: usize,
+    pub max_diff_lines: Option<usize>,
+    pub color: bool,
+    // Flag stages that got this many percent slower, if set
+    pub timing_threshold: Option<f64>,
+    // ...and by at least this many microseconds, to ignore noise on tiny files
+    pub timing_min_us: i64,
+}
+
+enum FieldValue<'a> {
+    Text(Option<&'a str>),
+    Flag(Option<bool>),
+}
+
+type FieldGetter = for<'a> fn(&'a ParseData) -> FieldValue<'a>;
+
+// Everything `diff` compares, in the order it reports them. Backtraces contain
+// addresses and line numbers that move with every build, so panics are only
+// compared by where and why.
+const FIELDS: &[(&str, FieldGetter)] = &[
+    ("output", |d| text(&d.output)),
+    ("entrypoint", |d| text(&d.entrypoint)),
+    ("error", |d| text(&d.error)),
+    ("fmt_output", |d| text(&d.fmt_output)),
+    ("reparse_output", |d| text(&d.reparse_output)),
+    ("reparse_error", |d| text(&d.reparse_error)),
+    ("normalized_output", |d| text(&d.normalized_output)),
+    ("normalized_reparse_output", |d| {
+        text(&d.normalized_reparse_output)
+    }),
+    ("double_fmt_output", |d| text(&d.double_fmt_output)),
+    ("fmt_changed", |d| text(&d.fmt_changed)),
+    ("fmt_changed_syntax", |d| {
+        FieldValue::Flag(d.fmt_changed_syntax)
+    }),
+    ("fmt_idempotent", |d| FieldValue::Flag(d.fmt_idempotent)),
+    ("fmt_convergence", |d| text(&d.fmt_convergence)),
+    ("fmt_hygienic", |d| FieldValue::Flag(d.fmt_hygienic)),
+    ("fmt_hygiene_violations", |d| {
+        text(&d.fmt_hygiene_violations)
+    }),
+    ("comments_preserved", |d| {
+        FieldValue::Flag(d.comments_preserved)
+    }),
+    ("comments_lost", |d| text(&d.comments_lost)),
+    ("comments_duplicated", |d| text(&d.comments_duplicated)),
+    ("comments_moved", |d| text(&d.comments_moved)),
+    ("panic_stage", |d| text(&d.panic_stage)),
+    ("panic_message", |d| text(&d.panic_message)),
+    ("outcome", |d| text(&d.outcome)),
+];
+
+fn text(value: &Option<String>) -> FieldValue<'_> {
+    FieldValue::Text(value.as_deref())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Class {
+    Regression,
+    Fix,
+    Neutral,
+}
+
+impl Class {
+    fn name(self) -> &'static str {
+        match self {
+            Class::Regression => "regression",
+            Class::Fix => "fix",
+            Class::Neutral => "neutral",
+        }
+    }
+}
+
+// (transition, class) pairs for every verdict that changed between `a` and
+// `b`. A file can have several, e.g. a parse fix that uncovers a formatter
+// regression.
+fn classify(a: &ParseData, b: &ParseData) -> Vec<(&'static str, Class)> {
+    fn flip(
+        transitions: &mut Vec<(&'static str, Class)>,
+        was_ok: Option<bool>,
+        is_ok: Option<bool>,
+        fixed: &'static str,
+        regressed: &'static str,
+    ) {
+        match (was_ok, is_ok) {
+            (Some(false), Some(true)) => transitions.push((fixed, Class::Fix)),
+            (Some(true), Some(false)) => transitions.push((regressed, Class::Regression)),
+            _ => {}
+        }
+    }
+
+    // Rows from before `outcome` existed have it NULL, and were all parsed
+    // in-process to completion.
+    let completed = |d: &ParseData| {
+        Some(d.outcome.as_deref().unwrap_or(OUTCOME_COMPLETED) == OUTCOME_COMPLETED)
+    };
+    // A panic hides the verdicts of its own stage and everything after it.
+    let parsed = |d: &ParseData| {
+        (completed(d) == Some(true) && d.panic_stage.as_deref() != Some("parse"))
+            .then_some(d.error.is_none())
+    };
+    let reparsed = |d: &ParseData| {
+        (d.fmt_output.is_some() && d.panic_stage.as_deref() != Some("reparse"))
+            .then_some(d.reparse_error.is_none())
+    };
+
+    let mut transitions = Vec::new();
+    flip(
+        &mut transitions,
+        completed(a),
+        completed(b),
+        "outcome_fixed",
+        "outcome_regressed",
+    );
+    flip(
+        &mut transitions,
+        Some(a.panic_stage.is_none()),
+        Some(b.panic_stage.is_none()),
+        "panic_fixed",
+        "new_panic",
+    );
+    flip(
+        &mut transitions,
+        parsed(a),
+        parsed(b),
+        "parse_fixed",
+        "parse_regressed",
+    );
+    // A file that falls back to a later entrypoint has stopped parsing the
+    // way it did, e.g. its header no longer parses; it's only a fix the other
+    // way round.
+    match (entrypoint_rank(a), entrypoint_rank(b)) {
+        (Some(was), Some(is)) if is > was => {
+            transitions.push(("entrypoint_regressed", Class::Regression))
+        }
+        (Some(was), Some(is)) if is < was => transitions.push(("entrypoint_fixed", Class::Fix)),
+        _ => {}
+    }
+    flip(
+        &mut transitions,
+        reparsed(a),
+        reparsed(b),
+        "reparse_fixed",
+        "reparse_regressed",
+    );
+    flip(
+        &mut transitions,
+        a.fmt_idempotent,
+        b.fmt_idempotent,
+        "fmt_idempotence_fixed",
+        "fmt_idempotence_regressed",
+    );
+    flip(
+        &mut transitions,
+        a.fmt_changed_syntax.map(|changed| !changed),
+        b.fmt_changed_syntax.map(|changed| !changed),
+        "fmt_syntax_fixed",
+        "fmt_syntax_regressed",
+    );
+    flip(
+        &mut transitions,
+        a.comments_preserved,
+        b.comments_preserved,
+        "comments_fixed",
+        "comments_regressed",
+    );
+    flip(
+        &mut transitions,
+        a.fmt_hygienic,
+        b.fmt_hygienic,
+        "fmt_hygiene_fixed",
+        "fmt_hygiene_regressed",
+    );
+    transitions
+}
+
+// Position in the order entrypoints are tried, for files that parsed. Rows from
+// before `entrypoint` existed have it NULL, and only ever parsed as modules.
+fn entrypoint_rank(d: &ParseData) -> Option<usize> {
+    let name = match d.entrypoint.as_deref() {
+        Some(name) => name,
+        None if d.output.is_some() => Entrypoint::Module.name(),
+        None => return None,
+    };
+    Entrypoint::ALL.iter().position(|e| e.name() == name)
+}
+
+type TimingGetter = fn(&ParseData) -> Option<i64>;
+
+const TIMINGS: &[(&str, TimingGetter)] = &[
+    ("parse_slower", |d| d.parse_us),
+    ("format_slower", |d| d.format_us),
+    ("reparse_slower", |d| d.reparse_us),
+    ("normalize_slower", |d| d.normalize_us),
+    ("double_format_slower", |d| d.double_format_us),
+];
+
+// Timings are noisy, so they're only compared when asked for, and a slowdown
+// counts as a regression so that `diff` can gate on it like on the others.
+fn timing_regressions(
+    a: &ParseData,
+    b: &ParseData,
+    options: &DiffOptions,
+) -> Vec<(&'static str, Class, i64, i64)> {
+    let Some(threshold) = options.timing_threshold else {
+        return Vec::new();
+    };
+    TIMINGS
+        .iter()
+        .filter_map(|(name, get)| {
+            let (a_us, b_us) = (get(a)?, get(b)?);
+            let slower = b_us - a_us >= options.timing_min_us
+                && b_us as f64 > a_us as f64 * (1.0 + threshold / 100.0);
+            slower.then_some((*name, Class::Regression, a_us, b_us))
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct Timing {
+    a_us: i64,
+    b_us: i64,
+}
+
+#[derive(Serialize)]
+struct FileDiff<'a> {
+    repo_url: &'a str,
+    file_path: &'a str,
+    // None when one of the runs has no result for the file
+    class: Option<Class>,
+    transitions: Vec<&'static str>,
+    differences: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    panic_stage: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    panic_message: Option<&'a str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    diffs: BTreeMap<&'static str, String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    timings: BTreeMap<&'static str, Timing>,
+    // Where b's formatting first changed the AST, with the original and
+    // formatted source there side by side
+    #[serde(skip_serializing_if = "Option::is_none")]
+    divergence: Option<Divergence>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    excerpt: Option<String>,
+}
+
+#[derive(Default, Serialize)]
+pub struct DiffSummary {
+    pub regressions: usize,
+    pub fixes: usize,
+    pub neutral: usize,
+    pub missing: usize,
+    transitions: BTreeMap<&'static str, TransitionCount>,
+}
+
+#[derive(Serialize)]
+struct TransitionCount {
+    class: Class,
+    files: usize,
+}
+
+pub fn diff_runs(
+    entries: &[CorpusEntry],
+    conn_a: &Connection,
+    run_a: i64,
+    conn_b: &Connection,
+    run_b: i64,
+    options: &DiffOptions,
+) -> Result<DiffSummary> {
+    let mut summary = DiffSummary::default();
+
+    for CorpusEntry {
+        repo_url,
+        file_path,
+        ..
+    } in entries
+    {
+        let result_a = load_result(conn_a, run_a, repo_url, file_path)?;
+        let result_b = load_result(conn_b, run_b, repo_url, file_path)?;
+
+        let (Some(a), Some(b)) = (&result_a, &result_b) else {
+            summary.missing += 1;
+            report(
+                &FileDiff {
+                    repo_url,
+                    file_path,
+                    class: None,
+                    transitions: Vec::new(),
+                    differences: Vec::new(),
+                    panic_stage: None,
+                    panic_message: None,
+                    diffs: BTreeMap::new(),
+                    timings: BTreeMap::new(),
+                    divergence: None,
+                    excerpt: None,
+                },
+                options,
+            );
+            continue;
+        };
+
+        let mut differences = Vec::new();
+        let mut diffs = BTreeMap::new();
+        for (name, get) in FIELDS {
+            match (get(a), get(b)) {
+                (FieldValue::Text(a), FieldValue::Text(b)) if a != b => {
+                    differences.push(*name);
+                    if options.show_diff {
+                        diffs.insert(*name, unified_diff(a, b, options));
+                    }
+                }
+                (FieldValue::Flag(a), FieldValue::Flag(b)) if a != b => {
+                    differences.push(*name);
+                }
+                _ => {}
+            }
+        }
+
+        let mut transitions = classify(a, b);
+        let mut timings = BTreeMap::new();
+        for (name, class, a_us, b_us) in timing_regressions(a, b, options) {
+            transitions.push((name, class));
+            timings.insert(name, Timing { a_us, b_us });
+        }
+
+        if differences.is_empty() && transitions.is_empty() {
+            continue;
+        }
+
+        let class = transitions
+            .iter()
+            .map(|(_, class)| *class)
+            .min()
+            .unwrap_or(Class::Neutral);
+        match class {
+            Class::Regression => summary.regressions += 1,
+            Class::Fix => summary.fixes += 1,
+            Class::Neutral => summary.neutral += 1,
+        }
+        for (name, class) in &transitions {
+            summary
+                .transitions
+                .entry(name)
+                .or_insert(TransitionCount {
+                    class: *class,
+                    files: 0,
+                })
+                .files += 1;
+        }
+
+        let (panic_stage, panic_message) = match (&a.panic_stage, &b.panic_stage) {
+            (None, Some(stage)) => (Some(stage), b.panic_message.as_deref()),
+            (Some(stage), None) => (Some(stage), a.panic_message.as_deref()),
+            _ => (None, None),
+        };
+
+        let divergence = divergence(b);
+        let excerpt = match (&divergence, &b.fmt_output) {
+            (Some(divergence), Some(formatted)) => {
+                load_contents(conn_b, run_b, repo_url, file_path)?.map(|contents| {
+                    side_by_side(
+                        &contents,
+                        divergence.start.zip(divergence.end),
+                        formatted,
+                        divergence.fmt_start.zip(divergence.fmt_end),
+                    )
+                })
+            }
+            _ => None,
+        };
+
+        report(
+            &FileDiff {
+                repo_url,
+                file_path,
+                class: Some(class),
+                transitions: transitions.iter().map(|(name, _)| *name).collect(),
+                differences,
+                panic_stage: panic_stage.map(String::as_str),
+                panic_message,
+                diffs,
+                timings,
+                divergence,
+                excerpt,
+            },
+            options,
+        );
+    }
+
+    report_summary(&summary, options);
+
+    Ok(summary)
+}
+
+fn divergence(data: &ParseData) -> Option<Divergence> {
+    Some(Divergence {
+        path: data.divergence_path.clone()?,
+        kind: data.divergence_kind.clone().unwrap_or_default(),
+        start: data.divergence_start,
+        end: data.divergence_end,
+        fmt_start: data.divergence_fmt_start,
+        fmt_end: data.divergence_fmt_end,
+    })
+}
+
+fn report(file: &FileDiff, options: &DiffOptions) {
+    if options.format == OutputFormat::Json {
+        println!("{}", serde_json::to_string(file).unwrap());
+        return;
+    }
+
+    let (repo_url, file_path) = (file.repo_url, file.file_path);
+    let Some(class) = file.class else {
+        println!("No results found for file: {} {}", repo_url, file_path);
+        return;
+    };
+
+    let label = match class {
+        Class::Regression => colorize_with("REGRESSION", "31", options.color),
+        Class::Fix => colorize_with("fix", "32", options.color),
+        Class::Neutral => "neutral".to_string(),
+    };
+    if file.transitions.is_empty() {
+        println!(
+            "{} {} {}: {}",
+            label,
+            repo_url,
+            file_path,
+            file.differences.join(", ")
+        );
+    } else if file.differences.is_empty() {
+        println!(
+            "{} {} {}: {}",
+            label,
+            repo_url,
+            file_path,
+            file.transitions.join(", ")
+        );
+    } else {
+        println!(
+            "{} {} {}: {} ({})",
+            label,
+            repo_url,
+            file_path,
+            file.transitions.join(", "),
+            file.differences.join(", ")
+        );
+    }
+
+    if let (Some(stage), Some(message)) = (file.panic_stage, file.panic_message) {
+        println!("    panic in {}: {}", stage, message);
+    }
+
+    for (name, timing) in &file.timings {
+        println!(
+            "    {}: {}us -> {}us",
+            name.trim_end_matches("_slower"),
+            timing.a_us,
+            timing.b_us
+        );
+    }
+
+    if let Some(divergence) = &file.divergence {
+        println!(
+            "    syntax diverges at {} ({})",
+            divergence.path, divergence.kind
+        );
+        if let Some(excerpt) = &file.excerpt {
+            for line in excerpt.lines() {
+                println!("    {}", line);
+            }
+        }
+    }
+
+    for (name, diff) in &file.diffs {
+        println!("--- {} (a)", name);
+        println!("+++ {} (b)", name);
+        for line in diff.lines() {
+            println!("{}", colorize(line, options.color));
+        }
+    }
+}
+
+fn report_summary(summary: &DiffSummary, options: &DiffOptions) {
+    if options.format == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::to_string(&serde_json::json!({ "summary": summary })).unwrap()
+        );
+        return;
+    }
+
+    println!();
+    println!("{:<12} {:<28} {:>8}", "class", "transition", "files");
+    let mut rows: Vec<_> = summary.transitions.iter().collect();
+    rows.sort_by_key(|(name, count)| (count.class, *name));
+    for (name, count) in rows {
+        println!("{:<12} {:<28} {:>8}", count.class.name(), name, count.files);
+    }
+    println!();
+    println!(
+        "{} regressions, {} fixes, {} neutral changes, {} files missing from a run",
+        summary.regressions, summary.fixes, summary.neutral, summary.missing
+    );
+}
+
+pub fn unified_diff(a: Option<&str>, b: Option<&str>, options: &DiffOptions) -> String {
+    let (a, b) = (a.unwrap_or(""), b.unwrap_or(""));
+    let diff = TextDiff::from_lines(a, b);
+
+    let mut out = String::new();
+    let mut lines = 0;
+    for hunk in diff
+        .unified_diff()
+        .context_radius(options.context)
+        .iter_hunks()
+    {
+        out.push_str(&hunk.header().to_string());
+        out.push('\n');
+        for change in hunk.iter_changes() {
+            if options.max_diff_lines.is_some_and(|max| lines >= max) {
+                let remaining = diff
+                    .iter_all_changes()
+                    .filter(|c| c.tag() != ChangeTag::Equal)
+                    .count();
+                out.push_str(&format!(
+                    "... diff truncated ({} changed lines total)\n",
+                    remaining
+                ));
+                return out;
+            }
+            let sign = match change.tag() {
+                ChangeTag::Delete => '-',
+                ChangeTag::Insert => '+',
+                ChangeTag::Equal => ' ',
+            };
+            out.push(sign);
+            out.push_str(change.value());
+            if change.missing_newline() {
+                out.push_str("\n\\ No newline at end of field\n");
+            }
+            lines += 1;
+        }
+    }
+    out
+}
+
+pub fn colorize(line: &str, color: bool) -> String {
+    let code = match line.as_bytes().first() {
+        Some(b'-') => "31",
+        Some(b'+') => "32",
+        Some(b'@') => "36",
+        _ => return line.to_string(),
+    };
+    colorize_with(line, code, color)
+}
+
+fn colorize_with(text: &str, code: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_outcome_counts_as_completed() {
+        let baseline = ParseData {
+            outcome: None,
+            error: Some("Header(Start(@0))".to_string()),
+            ..ParseData::default()
+        };
+        let current = ParseData {
+            outcome: Some(OUTCOME_COMPLETED.to_string()),
+            fmt_output: Some(String::new()),
+            ..ParseData::default()
+        };
+
+        assert_eq!(
+            classify(&baseline, &current),
+            vec![("parse_fixed", Class::Fix)]
+        );
+        assert_eq!(
+            classify(&current, &baseline),
+            vec![("parse_regressed", Class::Regression)]
+        );
+    }
+
+    #[test]
+    fn falling_back_from_module_is_a_regression() {
+        let module = ParseData {
+            output: Some("Module".to_string()),
+            ..ParseData::default()
+        };
+        let defs = ParseData {
+            output: Some("Defs".to_string()),
+            entrypoint: Some("defs".to_string()),
+            ..ParseData::default()
+        };
+
+        assert_eq!(
+            classify(&module, &defs),
+            vec![("entrypoint_regressed", Class::Regression)]
+        );
+        assert_eq!(
+            classify(&defs, &module),
+            vec![("entrypoint_fixed", Class::Fix)]
+        );
+    }
+}
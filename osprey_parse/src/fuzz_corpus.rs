@@ -0,0 +1,112 @@
+use rusqlite::{Connection, Result};
+use scraper::{Html, Selector};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use crate::corpus::CorpusEntry;
+use crate::minimize;
+use crate::worker::{WorkerLimits, WorkerProcess};
+
+pub struct SeedOptions<'a> {
+    pub out: &'a Path,
+    pub zulip_db: Option<&'a str>,
+    pub max_len: usize,
+    // Inputs `parse_one` already records a failure for are left out unless
+    // this is set, since `fmt_module` would stop on the first of them. The
+    // check runs in a worker with these limits.
+    pub keep_failing: bool,
+    pub limits: WorkerLimits,
+}
+
+// Seeds for the targets in `fuzz/`: the selected `roc_files`, then every code
+// block in zulip_code_blocks.db, one file per distinct input. Files are named
+// by the hash of their contents, so exporting into a corpus the fuzzer has
+// already grown only adds what's missing.
+pub fn export_seeds(
+    conn_corpus: &Connection,
+    entries: &[CorpusEntry],
+    options: &SeedOptions,
+) -> Result<()> {
+    let mut inputs = Vec::new();
+    let mut stmt = conn_corpus.prepare("SELECT file_contents FROM roc_files WHERE id = ?1")?;
+    for entry in entries {
+        inputs.push(stmt.query_row([entry.id], |row| row.get::<_, String>(0))?);
+    }
+    if let Some(zulip_db) = options.zulip_db {
+        inputs.extend(zulip_code_blocks(&Connection::open(zulip_db)?)?);
+    }
+
+    if let Err(e) = fs::create_dir_all(options.out) {
+        eprintln!("Failed to create {}: {}", options.out.display(), e);
+        std::process::exit(1);
+    }
+
+    let mut process = (!options.keep_failing).then(|| WorkerProcess::new(options.limits.clone()));
+    let mut seen = HashSet::new();
+    let (mut written, mut existing, mut duplicates, mut too_long, mut failing) = (0, 0, 0, 0, 0);
+    for input in inputs {
+        if input.trim().is_empty() {
+            continue;
+        }
+        if input.len() > options.max_len {
+            too_long += 1;
+            continue;
+        }
+        let name = format!("{:x}", Sha256::digest(input.as_bytes()));
+        if !seen.insert(name.clone()) {
+            duplicates += 1;
+            continue;
+        }
+        let path = options.out.join(name);
+        if path.exists() {
+            existing += 1;
+            continue;
+        }
+        if let Some(process) = &mut process {
+            if !minimize::failures(&process.parse(&input)).is_empty() {
+                failing += 1;
+                continue;
+            }
+        }
+        if let Err(e) = fs::write(&path, &input) {
+            eprintln!("Failed to write seed {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+        written += 1;
+    }
+
+    println!(
+        "Exported {} seeds to {} ({} already there, {} duplicates, {} longer than {} bytes, \
+         {} already failing)",
+        written,
+        options.out.display(),
+        existing,
+        duplicates,
+        too_long,
+        options.max_len,
+        failing
+    );
+    Ok(())
+}
+
+// The messages are stored as the HTML zulip renders, so code blocks are picked
+// out the same way `osprey_zulip` does. Whether they're actually roc doesn't
+// matter to the fuzzer.
+fn zulip_code_blocks(conn: &Connection) -> Result<Vec<String>> {
+    let code_selector = Selector::parse("code, pre").unwrap();
+    let mut stmt = conn.prepare("SELECT content FROM messages ORDER BY message_id")?;
+    let messages = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut blocks = Vec::new();
+    for content in messages {
+        let document = Html::parse_document(&content);
+        for code_block in document.select(&code_selector) {
+            blocks.push(code_block.text().collect::<Vec<_>>().join(""));
+        }
+    }
+    Ok(blocks)
+}
@@ -0,0 +1,874 @@
+// Random programs for the parser and formatter. The corpus only has the
+// syntax people happened to write; these get at combinations nobody did.
+//
+// A program is built directly as `roc_parse::ast` values in an arena:
+// expressions, patterns, type annotations, defs and a header, each
+// shaped the way the parser would produce it (a parenthesized expression is
+// a `ParensAround`; patterns and types have no such node and leave the
+// parentheses to the formatter). `check` formats the tree with roc_fmt for
+// its source and runs the stages `parse_one` runs after parsing, so the
+// reparse is compared with the generated tree itself.
+//
+// A program depends only on its seed and budget, so any failure can be
+// replayed with `generate --seed N --count 1 --budget B`.
+
+use bumpalo::collections::Vec as BumpVec;
+use bumpalo::Bump;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use roc_module::called_via::{BinOp, CalledVia};
+use roc_parse::ast::{
+    AssignedField, Collection, CommentOrNewline, Defs, Expr, FunctionArrow, Header, Module,
+    Pattern, Spaced, Spaces, StrLiteral, Tag, TypeAnnotation, TypeDef, TypeHeader, ValueDef,
+    WhenBranch,
+};
+use roc_parse::header::{
+    AppHeader, ExposedName, ExposesKeyword, ImportsKeyword, KeywordItem, ModuleHeader, ModuleName,
+    ModuleParams, PackageEntry, PackageHeader, PackageName, PackagesKeyword, PlatformHeader,
+    PlatformRequires, ProvidesKeyword, RequiresKeyword, TypedIdent,
+};
+use roc_parse::ident::UppercaseIdent;
+use roc_region::all::{Loc, Region};
+
+use crate::{
+    comments, format_parsed, minimize, record_comments, record_stages, run_fmt_stages, Output,
+    ParseData, Parsed, Stage,
+};
+
+// The `repo_url` of generated programs' results rows
+pub const REPO: &str = "generated";
+
+// Keeps the recursion (ours, the parser's and the formatter's) shallow even
+// with a large budget; past it, only leaves are generated.
+const MAX_DEPTH: usize = 8;
+const MAX_TOP_LEVEL_DEFS: usize = 20;
+
+const IDENTS: &[&str] = &[
+    "x", "y", "acc", "item", "count", "name", "list", "value", "total", "state", "next", "rest",
+];
+const MODULES: &[&str] = &["List", "Str", "Num", "Dict", "Result"];
+const TAGS: &[&str] = &["Ok", "Err", "Red", "Green", "Leaf", "Node", "Empty", "Pair"];
+const FIELDS: &[&str] = &["id", "name", "size", "left", "right", "kind"];
+// Only what the parser keeps as `Num` and `Float`: hex and binary literals
+// come back as `NonBase10Int`.
+const INTEGERS: &[&str] = &["0", "1", "2", "42", "1_000"];
+const FLOATS: &[&str] = &["3.14", "0.5"];
+// Strings with escapes come back as `StrLiteral::Line` segments, so only
+// plain ones are generated.
+const STRINGS: &[&str] = &["", "hello", "roc", "a b c"];
+const COMMENTS: &[&str] = &["TODO", "helper", "see below", "edge case", "keep in sync"];
+
+// (shorthand, location) for the packages a header depends on. An app's
+// platform is always `pf`.
+const PACKAGES: &[(&str, &str)] = &[
+    ("json", "https://example.com/json.tar.br"),
+    ("parser", "../parser/main.roc"),
+    ("html", "https://example.com/html.tar.br"),
+];
+const PLATFORM: &str = "https://example.com/platform.tar.br";
+const PLATFORM_NAMES: &[&str] = &["cli", "web", "roc-lang/basic"];
+
+const TYPE_NAMES: &[&str] = &["Str", "U8", "U64", "I64", "F64", "Dec", "Bool"];
+// With their arities
+const TYPE_CONSTRUCTORS: &[(&str, usize)] = &[("List", 1), ("Set", 1), ("Result", 2), ("Dict", 2)];
+const TYPE_VARIABLES: &[&str] = &["a", "b", "elem", "err"];
+const ALIASES: &[&str] = &["Shape", "Tree", "Config", "Event"];
+
+// Arithmetic, boolean and pipe operators chain freely. Comparisons don't
+// associate, so they only ever join two operands.
+const CHAIN_OPERATORS: &[BinOp] = &[
+    BinOp::Plus,
+    BinOp::Minus,
+    BinOp::Star,
+    BinOp::Slash,
+    BinOp::DoubleSlash,
+    BinOp::Percent,
+    BinOp::And,
+    BinOp::Or,
+    BinOp::Pizza,
+];
+const COMPARISON_OPERATORS: &[BinOp] = &[
+    BinOp::Equals,
+    BinOp::NotEquals,
+    BinOp::LessThan,
+    BinOp::LessThanOrEq,
+    BinOp::GreaterThan,
+    BinOp::GreaterThanOrEq,
+];
+
+// Where an expression goes, which decides what it can be and whether the
+// source needs parentheses around it there.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Place {
+    // A def's body or a branch, which gets lines of its own and so can be a
+    // `when` or defs
+    Block,
+    // The expression after defs, which can't be more defs: the parser would
+    // read those as part of the same block
+    Continuation,
+    // An element, a field's value or an inline closure's body
+    Inline,
+    // A function argument or the target of a field access
+    Atom,
+    // An operand of a binary operator, which binds looser than application
+    Operand,
+    // After `if`, `when` or a guard's `if`, where a closure or an inline `if`
+    // wouldn't know where to stop
+    Condition,
+    // An `if`'s final `else`, where another `if` would be read as one more
+    // `else if` branch of this one
+    Else,
+}
+
+impl Place {
+    fn block(self) -> bool {
+        matches!(self, Place::Block | Place::Continuation)
+    }
+}
+
+pub struct Generator<'a> {
+    arena: &'a Bump,
+    rng: ChaCha8Rng,
+    // Nodes left to spend; once it runs out only leaves are generated
+    budget: usize,
+    // Every comment put in the tree, in source order, as `comments::comments`
+    // lexes them
+    comments: Vec<String>,
+}
+
+impl<'a> Generator<'a> {
+    pub fn new(arena: &'a Bump, seed: u64, budget: usize) -> Generator<'a> {
+        Generator {
+            arena,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            budget,
+            comments: Vec::new(),
+        }
+    }
+
+    // A file with a header, bare defs or a single expression, a third of the
+    // time each. Top-level defs are added until the budget is spent.
+    pub fn program(&mut self) -> Parsed<'a> {
+        if self.rng.gen_range(0..3) == 0 {
+            return Parsed::Expr(self.expr(0, Place::Block));
+        }
+
+        let mut defs = Defs::default();
+        let mut names = Vec::new();
+        while names.len() < MAX_TOP_LEVEL_DEFS && (names.is_empty() || self.budget > 0) {
+            let spaces_before = self.comment(names.is_empty(), true);
+            if self.rng.gen_range(0..10) < 2 {
+                let (name, type_def) = self.alias();
+                names.push(Some(name));
+                defs.push_type_def(type_def, Region::zero(), spaces_before, &[]);
+            } else {
+                let (name, value_def) = self.value_def(0);
+                names.push(name);
+                defs.push_value_def(value_def, Region::zero(), spaces_before, &[]);
+            }
+        }
+
+        if self.rng.gen_bool(0.5) {
+            return Parsed::Defs(defs);
+        }
+        let header = self.header(names.into_iter().flatten());
+        Parsed::Module(self.alloc(Output {
+            header: Module {
+                comments: &[],
+                header,
+            },
+            module_defs: defs,
+        }))
+    }
+
+    // Any of the headers but `hosted`, which only platforms' host modules
+    // use. What a module exposes or an app or platform provides is some of
+    // the names its defs define.
+    fn header(&mut self, names: impl Iterator<Item = &'a str>) -> Header<'a> {
+        let mut exposed = Vec::new();
+        for name in names {
+            if !exposed.contains(&name) && self.rng.gen_bool(0.5) {
+                exposed.push(name);
+            }
+        }
+        let exposed = Collection::with_items(
+            self.slice(
+                exposed
+                    .into_iter()
+                    .map(|name| Loc::at_zero(Spaced::Item(ExposedName::new(name)))),
+            ),
+        );
+
+        match self.rng.gen_range(0..4) {
+            0 => Header::App(AppHeader {
+                before_provides: &[],
+                provides: exposed,
+                before_packages: &[],
+                packages: Loc::at_zero(self.packages(true)),
+                old_imports: None,
+                old_provides_to_new_package: None,
+            }),
+            1 => Header::Package(PackageHeader {
+                before_exposes: &[],
+                exposes: self.module_names(),
+                before_packages: &[],
+                packages: Loc::at_zero(self.packages(false)),
+            }),
+            2 => {
+                let requires = self.platform_requires();
+                let exposes = self.module_names();
+                let packages = self.packages(false);
+                Header::Platform(PlatformHeader {
+                    before_name: &[],
+                    name: Loc::at_zero(PackageName::from(self.pick(PLATFORM_NAMES))),
+                    requires: keyword_item(RequiresKeyword, requires),
+                    exposes: keyword_item(ExposesKeyword, exposes),
+                    packages: keyword_item(PackagesKeyword, packages),
+                    imports: keyword_item(ImportsKeyword, Collection::empty()),
+                    provides: keyword_item(ProvidesKeyword, exposed),
+                })
+            }
+            _ => {
+                let params = self.rng.gen_bool(0.3).then(|| self.module_params());
+                Header::Module(ModuleHeader {
+                    after_keyword: &[],
+                    params,
+                    exposes: exposed,
+                    interface_imports: None,
+                })
+            }
+        }
+    }
+
+    // `{ pf: platform "…", json: "…" }`; an app's always has its platform
+    // first.
+    fn packages(&mut self, app: bool) -> Collection<'a, Loc<Spaced<'a, PackageEntry<'a>>>> {
+        let mut entries = Vec::new();
+        if app {
+            entries.push(PackageEntry {
+                shorthand: "pf",
+                spaces_after_shorthand: &[],
+                platform_marker: Some(&[]),
+                package_name: Loc::at_zero(PackageName::from(PLATFORM)),
+            });
+        }
+        let n = self.rng.gen_range(0..=2);
+        for &(shorthand, location) in PACKAGES.choose_multiple(&mut self.rng, n) {
+            entries.push(PackageEntry {
+                shorthand,
+                spaces_after_shorthand: &[],
+                platform_marker: None,
+                package_name: Loc::at_zero(PackageName::from(location)),
+            });
+        }
+        Collection::with_items(
+            self.slice(
+                entries
+                    .into_iter()
+                    .map(|entry| Loc::at_zero(Spaced::Item(entry))),
+            ),
+        )
+    }
+
+    fn module_names(&mut self) -> Collection<'a, Loc<Spaced<'a, ModuleName<'a>>>> {
+        let n = self.rng.gen_range(1..=3);
+        let names = MODULES
+            .choose_multiple(&mut self.rng, n)
+            .map(|&name| Loc::at_zero(Spaced::Item(ModuleName::new(name))))
+            .collect::<Vec<_>>();
+        Collection::with_items(self.slice(names))
+    }
+
+    // `requires { Model } { main : … }`
+    fn platform_requires(&mut self) -> PlatformRequires<'a> {
+        let n = self.rng.gen_range(0..=1);
+        let rigids = ALIASES
+            .choose_multiple(&mut self.rng, n)
+            .map(|&name| Loc::at_zero(Spaced::Item(UppercaseIdent::from(name))))
+            .collect::<Vec<_>>();
+        let signature = TypedIdent {
+            ident: Loc::at_zero("main"),
+            spaces_before_colon: &[],
+            ann: Loc::at_zero(self.type_annotation(0)),
+        };
+        PlatformRequires {
+            rigids: Collection::with_items(self.slice(rigids)),
+            signatures: Collection::with_items(self.slice([Loc::at_zero(Spaced::Item(signature))])),
+        }
+    }
+
+    // `module { x, name } -> […]`
+    fn module_params(&mut self) -> ModuleParams<'a> {
+        let n = self.rng.gen_range(1..=3);
+        let fields = IDENTS
+            .choose_multiple(&mut self.rng, n)
+            .map(|&ident| Loc::at_zero(Pattern::Identifier { ident }))
+            .collect::<Vec<_>>();
+        ModuleParams {
+            pattern: Loc::at_zero(Collection::with_items(self.slice(fields))),
+            before_arrow: &[],
+            after_arrow: &[],
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        if self.budget == 0 {
+            return false;
+        }
+        self.budget -= 1;
+        true
+    }
+
+    // Whether to build a compound node rather than a leaf at this depth, which
+    // gets less likely the deeper it is, so the budget goes on breadth too.
+    fn nest(&mut self, depth: usize) -> bool {
+        depth < MAX_DEPTH && self.rng.gen_range(0..MAX_DEPTH) >= depth && self.take()
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        *items.choose(&mut self.rng).unwrap()
+    }
+
+    fn alloc<T>(&self, value: T) -> &'a T {
+        self.arena.alloc(value)
+    }
+
+    fn slice<T>(&self, items: impl IntoIterator<Item = T>) -> &'a [T] {
+        BumpVec::from_iter_in(items, self.arena).into_bump_slice()
+    }
+
+    fn ident(&mut self) -> &'a str {
+        let ident = self.pick(IDENTS);
+        if self.rng.gen_bool(0.2) {
+            let n = self.rng.gen_range(1..10);
+            self.arena.alloc_str(&format!("{}{}", ident, n))
+        } else {
+            ident
+        }
+    }
+
+    fn fields(&mut self, min: usize, max: usize) -> Vec<&'static str> {
+        let n = self.rng.gen_range(min..=max);
+        FIELDS.choose_multiple(&mut self.rng, n).copied().collect()
+    }
+
+    // The spaces before a def: now and then a comment on the line above it,
+    // which after an earlier def follows the newline that ended that one.
+    fn comment(&mut self, first: bool, top_level: bool) -> &'a [CommentOrNewline<'a>] {
+        if !self.rng.gen_bool(if top_level { 0.2 } else { 0.15 }) {
+            return &[];
+        }
+        let text = self.pick(COMMENTS);
+        let comment = if top_level && self.rng.gen_bool(0.5) {
+            self.comments.push(format!("## {}", text));
+            CommentOrNewline::DocComment(self.arena.alloc_str(&format!(" {}", text)))
+        } else {
+            self.comments.push(format!("# {}", text));
+            CommentOrNewline::LineComment(self.arena.alloc_str(&format!(" {}", text)))
+        };
+        if first {
+            self.slice([comment])
+        } else {
+            self.slice([CommentOrNewline::Newline, comment])
+        }
+    }
+
+    fn alias(&mut self) -> (&'a str, TypeDef<'a>) {
+        let name = self.pick(ALIASES);
+        let n = self.rng.gen_range(0..=2);
+        let vars = TYPE_VARIABLES
+            .choose_multiple(&mut self.rng, n)
+            .map(|&ident| Loc::at_zero(Pattern::Identifier { ident }))
+            .collect::<Vec<_>>();
+        let vars = self.slice(vars);
+        let header = TypeHeader {
+            name: Loc::at_zero(name),
+            vars,
+        };
+        let ann = Loc::at_zero(self.type_annotation(0));
+        (name, TypeDef::Alias { header, ann })
+    }
+
+    // With the name it binds, unless it destructures.
+    fn value_def(&mut self, depth: usize) -> (Option<&'a str>, ValueDef<'a>) {
+        if self.rng.gen_bool(0.1) {
+            let pattern = if self.rng.gen_bool(0.5) {
+                Pattern::Tuple(Collection::with_items(self.patterns(
+                    depth + 1,
+                    2,
+                    3,
+                    false,
+                )))
+            } else {
+                self.record_destructure(1, 3)
+            };
+            let pattern = self.alloc(Loc::at_zero(pattern));
+            let body = self.expr(depth + 1, Place::Block);
+            return (
+                None,
+                ValueDef::Body(pattern, self.alloc(Loc::at_zero(body))),
+            );
+        }
+
+        let name = self.ident();
+        let pattern = self.alloc(Loc::at_zero(Pattern::Identifier { ident: name }));
+        let value_def = if self.rng.gen_bool(0.25) {
+            let ann_type = self.type_annotation(depth + 1);
+            let body_expr = self.expr(depth + 1, Place::Block);
+            ValueDef::AnnotatedBody {
+                ann_pattern: pattern,
+                ann_type: self.alloc(Loc::at_zero(ann_type)),
+                lines_between: self.slice([CommentOrNewline::Newline]),
+                body_pattern: pattern,
+                body_expr: self.alloc(Loc::at_zero(body_expr)),
+            }
+        } else {
+            let body = self.expr(depth + 1, Place::Block);
+            ValueDef::Body(pattern, self.alloc(Loc::at_zero(body)))
+        };
+        (Some(name), value_def)
+    }
+
+    fn expr(&mut self, depth: usize, place: Place) -> Expr<'a> {
+        let expr = if self.nest(depth) {
+            self.compound_expr(depth + 1, place)
+        } else {
+            self.leaf_expr()
+        };
+        self.parenthesize(expr, place)
+    }
+
+    fn parenthesize(&self, expr: Expr<'a>, place: Place) -> Expr<'a> {
+        let atom = matches!(
+            expr,
+            Expr::Num(_)
+                | Expr::Float(_)
+                | Expr::Str(_)
+                | Expr::Var { .. }
+                | Expr::Tag(_)
+                | Expr::List(_)
+                | Expr::Tuple(_)
+                | Expr::Record(_)
+                | Expr::RecordAccess(..)
+        );
+        let parens = match place {
+            Place::Block | Place::Continuation | Place::Inline => false,
+            Place::Atom => !atom,
+            Place::Operand => !atom && !matches!(expr, Expr::Apply(..)),
+            Place::Condition => matches!(expr, Expr::Closure(..) | Expr::If { .. }),
+            Place::Else => matches!(expr, Expr::If { .. }),
+        };
+        if parens {
+            Expr::ParensAround(self.alloc(expr))
+        } else {
+            expr
+        }
+    }
+
+    fn compound_expr(&mut self, depth: usize, place: Place) -> Expr<'a> {
+        if place.block() && self.rng.gen_bool(0.5) {
+            let kinds = if place == Place::Continuation { 3 } else { 4 };
+            return match self.rng.gen_range(0..kinds) {
+                0 => self.if_expr(depth, place),
+                1 => self.when_expr(depth),
+                2 => self.closure(depth, place),
+                _ => self.defs_expr(depth),
+            };
+        }
+        match self.rng.gen_range(0..10) {
+            0 => Expr::List(Collection::with_items(self.exprs(depth, 0, 3))),
+            1 => Expr::Tuple(Collection::with_items(self.exprs(depth, 2, 3))),
+            2 => {
+                let fields = self.fields(0, 3);
+                Expr::Record(self.record_fields(depth, fields))
+            }
+            3 => {
+                let target = if self.rng.gen_bool(0.5) {
+                    Expr::Var {
+                        module_name: "",
+                        ident: self.ident(),
+                    }
+                } else {
+                    let field = self.pick(FIELDS);
+                    Expr::Record(self.record_fields(depth, vec![field]))
+                };
+                Expr::RecordAccess(self.alloc(target), self.pick(FIELDS))
+            }
+            4 | 5 => {
+                let function = if self.rng.gen_bool(0.3) {
+                    Expr::Tag(self.pick(TAGS))
+                } else {
+                    self.var()
+                };
+                let n = self.rng.gen_range(1..=3);
+                let args = (0..n)
+                    .map(|_| {
+                        let arg = self.expr(depth, Place::Atom);
+                        self.alloc(Loc::at_zero(arg))
+                    })
+                    .collect::<Vec<_>>();
+                Expr::Apply(
+                    self.alloc(Loc::at_zero(function)),
+                    self.slice(args),
+                    CalledVia::Space,
+                )
+            }
+            6 | 7 => self.bin_ops(depth),
+            8 => self.closure(depth, Place::Inline),
+            _ => self.if_expr(depth, Place::Inline),
+        }
+    }
+
+    fn leaf_expr(&mut self) -> Expr<'a> {
+        match self.rng.gen_range(0..5) {
+            0 => Expr::Num(self.pick(INTEGERS)),
+            1 => Expr::Float(self.pick(FLOATS)),
+            2 => Expr::Str(StrLiteral::PlainLine(self.pick(STRINGS))),
+            3 => self.var(),
+            _ => Expr::Tag(self.pick(TAGS)),
+        }
+    }
+
+    fn var(&mut self) -> Expr<'a> {
+        let module_name = if self.rng.gen_bool(0.2) {
+            self.pick(MODULES)
+        } else {
+            ""
+        };
+        Expr::Var {
+            module_name,
+            ident: self.ident(),
+        }
+    }
+
+    fn exprs(&mut self, depth: usize, min: usize, max: usize) -> &'a [&'a Loc<Expr<'a>>] {
+        let n = self.rng.gen_range(min..=max);
+        let items = (0..n)
+            .map(|_| {
+                let item = self.expr(depth, Place::Inline);
+                self.alloc(Loc::at_zero(item))
+            })
+            .collect::<Vec<_>>();
+        self.slice(items)
+    }
+
+    fn record_fields(
+        &mut self,
+        depth: usize,
+        fields: Vec<&'static str>,
+    ) -> Collection<'a, Loc<AssignedField<'a, Expr<'a>>>> {
+        let fields = fields
+            .into_iter()
+            .map(|field| {
+                let value = self.expr(depth, Place::Inline);
+                Loc::at_zero(AssignedField::RequiredValue(
+                    Loc::at_zero(field),
+                    &[],
+                    self.alloc(Loc::at_zero(value)),
+                ))
+            })
+            .collect::<Vec<_>>();
+        Collection::with_items(self.slice(fields))
+    }
+
+    fn bin_ops(&mut self, depth: usize) -> Expr<'a> {
+        let (operators, n) = if self.rng.gen_bool(0.3) {
+            (COMPARISON_OPERATORS, 1)
+        } else {
+            (CHAIN_OPERATORS, self.rng.gen_range(1..=3))
+        };
+        let operands = (0..n)
+            .map(|_| {
+                let operand = self.expr(depth, Place::Operand);
+                (Loc::at_zero(operand), Loc::at_zero(self.pick(operators)))
+            })
+            .collect::<Vec<_>>();
+        let last = self.expr(depth, Place::Operand);
+        Expr::BinOps(self.slice(operands), self.alloc(Loc::at_zero(last)))
+    }
+
+    // Only a closure in a block can have a block for its body.
+    fn closure(&mut self, depth: usize, place: Place) -> Expr<'a> {
+        let args = self.patterns(depth, 1, 3, false);
+        let body_place = if place.block() {
+            Place::Block
+        } else {
+            Place::Inline
+        };
+        let body = self.expr(depth, body_place);
+        Expr::Closure(args, self.alloc(Loc::at_zero(body)))
+    }
+
+    // One in a block can have blocks in its branches; an inline one can't.
+    fn if_expr(&mut self, depth: usize, place: Place) -> Expr<'a> {
+        let (branch_place, max) = if place.block() {
+            (Place::Block, 3)
+        } else {
+            (Place::Inline, 2)
+        };
+        let n = self.rng.gen_range(1..=max);
+        let if_thens = (0..n)
+            .map(|_| {
+                let condition = self.expr(depth, Place::Condition);
+                let then = self.expr(depth, branch_place);
+                (Loc::at_zero(condition), Loc::at_zero(then))
+            })
+            .collect::<Vec<_>>();
+        let final_else = self.expr(depth, branch_place);
+        let final_else = self.parenthesize(final_else, Place::Else);
+        Expr::If {
+            if_thens: self.slice(if_thens),
+            final_else: self.alloc(Loc::at_zero(final_else)),
+            indented_else: false,
+        }
+    }
+
+    fn when_expr(&mut self, depth: usize) -> Expr<'a> {
+        let condition = self.expr(depth, Place::Condition);
+        let n = self.rng.gen_range(1..=3);
+        let branches = (0..n)
+            .map(|_| {
+                let patterns = self.patterns(depth, 1, 2, true);
+                let guard = if self.rng.gen_bool(0.2) {
+                    Some(Loc::at_zero(self.expr(depth, Place::Condition)))
+                } else {
+                    None
+                };
+                let value = Loc::at_zero(self.expr(depth, Place::Block));
+                self.alloc(WhenBranch {
+                    patterns,
+                    value,
+                    guard,
+                })
+            })
+            .collect::<Vec<_>>();
+        Expr::When(self.alloc(Loc::at_zero(condition)), self.slice(branches))
+    }
+
+    fn defs_expr(&mut self, depth: usize) -> Expr<'a> {
+        let mut defs = Defs::default();
+        for i in 0..self.rng.gen_range(1..=3) {
+            // A comment above the first def would belong to the expression
+            // rather than the def.
+            let spaces_before = if i == 0 {
+                &[]
+            } else {
+                self.comment(false, false)
+            };
+            let (_, value_def) = self.value_def(depth);
+            defs.push_value_def(value_def, Region::zero(), spaces_before, &[]);
+        }
+        let continuation = self.expr(depth, Place::Continuation);
+        Expr::Defs(self.alloc(defs), self.alloc(Loc::at_zero(continuation)))
+    }
+
+    // Only `when` branches can be `refutable`: closure arguments and
+    // destructuring defs get identifiers, `_`, tuples and records.
+    fn pattern(&mut self, depth: usize, refutable: bool) -> Pattern<'a> {
+        let compound = self.nest(depth);
+        let depth = depth + 1;
+        let kinds = if refutable { 8 } else { 4 };
+        match (self.rng.gen_range(0..kinds), compound) {
+            (0, true) => Pattern::Tuple(Collection::with_items(
+                self.patterns(depth, 2, 3, refutable),
+            )),
+            (1, true) => self.record_destructure(0, 3),
+            (0 | 2, _) => Pattern::Identifier {
+                ident: self.ident(),
+            },
+            (1 | 3, _) => Pattern::Underscore(""),
+            (4, _) => Pattern::NumLiteral(self.pick(INTEGERS)),
+            (5, _) => Pattern::StrLiteral(StrLiteral::PlainLine(self.pick(STRINGS))),
+            (6, true) => {
+                let n = self.rng.gen_range(0..=3);
+                let mut items = (0..n)
+                    .map(|_| Loc::at_zero(self.pattern(depth, true)))
+                    .collect::<Vec<_>>();
+                if self.rng.gen_bool(0.3) {
+                    items.push(Loc::at_zero(Pattern::ListRest(None)));
+                }
+                Pattern::List(Collection::with_items(self.slice(items)))
+            }
+            (_, compound) => {
+                let tag = Pattern::Tag(self.pick(TAGS));
+                let args = if compound {
+                    self.patterns(depth, 0, 2, true)
+                } else {
+                    &[]
+                };
+                if args.is_empty() {
+                    tag
+                } else {
+                    Pattern::Apply(self.alloc(Loc::at_zero(tag)), args)
+                }
+            }
+        }
+    }
+
+    fn patterns(
+        &mut self,
+        depth: usize,
+        min: usize,
+        max: usize,
+        refutable: bool,
+    ) -> &'a [Loc<Pattern<'a>>] {
+        let n = self.rng.gen_range(min..=max);
+        let patterns = (0..n)
+            .map(|_| Loc::at_zero(self.pattern(depth, refutable)))
+            .collect::<Vec<_>>();
+        self.slice(patterns)
+    }
+
+    fn record_destructure(&mut self, min: usize, max: usize) -> Pattern<'a> {
+        let fields = self.fields(min, max);
+        let fields = self.slice(
+            fields
+                .into_iter()
+                .map(|ident| Loc::at_zero(Pattern::Identifier { ident })),
+        );
+        Pattern::RecordDestructure(Collection::with_items(fields))
+    }
+
+    fn type_annotation(&mut self, depth: usize) -> TypeAnnotation<'a> {
+        if !self.nest(depth) {
+            return if self.rng.gen_bool(0.7) {
+                TypeAnnotation::Apply("", self.pick(TYPE_NAMES), &[])
+            } else {
+                TypeAnnotation::BoundVariable(self.pick(TYPE_VARIABLES))
+            };
+        }
+        let depth = depth + 1;
+        match self.rng.gen_range(0..5) {
+            0 => {
+                let (name, arity) = self.pick(TYPE_CONSTRUCTORS);
+                TypeAnnotation::Apply("", name, self.type_annotations(depth, arity, arity))
+            }
+            1 => {
+                let args = self.type_annotations(depth, 1, 3);
+                let ret = self.type_annotation(depth);
+                TypeAnnotation::Function(args, FunctionArrow::Pure, self.alloc(Loc::at_zero(ret)))
+            }
+            2 => {
+                let fields = self.fields(0, 3);
+                let fields = fields
+                    .into_iter()
+                    .map(|field| {
+                        let ann = self.type_annotation(depth);
+                        Loc::at_zero(AssignedField::RequiredValue(
+                            Loc::at_zero(field),
+                            &[],
+                            self.alloc(Loc::at_zero(ann)),
+                        ))
+                    })
+                    .collect::<Vec<_>>();
+                TypeAnnotation::Record {
+                    fields: Collection::with_items(self.slice(fields)),
+                    ext: None,
+                }
+            }
+            3 => TypeAnnotation::Tuple {
+                elems: Collection::with_items(self.type_annotations(depth, 2, 3)),
+                ext: None,
+            },
+            _ => {
+                let n = self.rng.gen_range(1..=3);
+                let names = TAGS
+                    .choose_multiple(&mut self.rng, n)
+                    .copied()
+                    .collect::<Vec<_>>();
+                let tags = names
+                    .into_iter()
+                    .map(|name| {
+                        Loc::at_zero(Tag::Apply {
+                            name: Loc::at_zero(name),
+                            args: self.type_annotations(depth, 0, 2),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                TypeAnnotation::TagUnion {
+                    ext: None,
+                    tags: Collection::with_items(self.slice(tags)),
+                }
+            }
+        }
+    }
+
+    fn type_annotations(
+        &mut self,
+        depth: usize,
+        min: usize,
+        max: usize,
+    ) -> &'a [Loc<TypeAnnotation<'a>>] {
+        let n = self.rng.gen_range(min..=max);
+        let annotations = (0..n)
+            .map(|_| Loc::at_zero(self.type_annotation(depth)))
+            .collect::<Vec<_>>();
+        self.slice(annotations)
+    }
+}
+
+fn keyword_item<'a, K, V>(keyword: K, item: V) -> KeywordItem<'a, K, V> {
+    KeywordItem {
+        keyword: Spaces {
+            before: &[],
+            item: keyword,
+            after: &[],
+        },
+        item,
+    }
+}
+
+// The source roc_fmt writes for a program.
+pub fn source(seed: u64, budget: usize) -> String {
+    let arena = Bump::new();
+    let program = Generator::new(&arena, seed, budget).program();
+    format_parsed(&program, &arena)
+}
+
+pub struct Checked {
+    pub source: String,
+    pub result: ParseData,
+    pub problems: Vec<String>,
+}
+
+// `parse_one` for a generated program, with the tree standing in for what
+// parsing its source would have produced. The source's comments are checked
+// against the ones the tree was given, since comparing them with the
+// reformatted source's would only check roc_fmt against itself.
+pub fn check(seed: u64, budget: usize) -> Checked {
+    let arena = Bump::new();
+    let mut source = String::new();
+    let result = record_stages(|result, stage| {
+        let mut generator = Generator::new(&arena, seed, budget);
+        let program = generator.program();
+        result.output = Some(format!("{:#?}", program));
+        result.entrypoint = Some(program.entrypoint().name().to_string());
+
+        *stage = Stage::Format;
+        source = format_parsed(&program, &arena);
+        run_fmt_stages(&source, &program, &arena, result, stage);
+        record_comments(&generator.comments, &comments::comments(&source), result);
+    });
+    let problems = problems(&result);
+    Checked {
+        source,
+        result,
+        problems,
+    }
+}
+
+// What the stages found wrong with a generated program.
+fn problems(data: &ParseData) -> Vec<String> {
+    let mut problems: Vec<String> = minimize::failures(data)
+        .iter()
+        .map(|f| f.describe())
+        .collect();
+    if data.comments_preserved == Some(false) {
+        problems.push("formatting loses or moves comments".to_string());
+    }
+    if data.fmt_hygienic == Some(false) {
+        problems.push("formatted output has whitespace problems".to_string());
+    }
+    problems
+}
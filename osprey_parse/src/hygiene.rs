@@ -0,0 +1,137 @@
+// Properties every `fmt_output` should have whether or not it reparses.
+// Lines inside `"""` block strings are the user's own text and only count
+// towards the final newline check.
+
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct Violation {
+    pub check: &'static str,
+    // 1-based
+    pub lines: Vec<usize>,
+}
+
+pub fn check_hygiene(formatted: &str) -> Vec<Violation> {
+    let mut trailing_whitespace = Vec::new();
+    let mut tabs = Vec::new();
+    let mut blank_lines = Vec::new();
+    let mut indentation = Vec::new();
+
+    let mut in_block_string = false;
+    let mut previous_blank = false;
+    for (i, line) in formatted.lines().enumerate() {
+        let number = i + 1;
+        let started_in_block_string = in_block_string;
+        in_block_string = ends_in_block_string(line, in_block_string);
+        if started_in_block_string {
+            previous_blank = false;
+            continue;
+        }
+
+        if line.ends_with([' ', '\t']) {
+            trailing_whitespace.push(number);
+        }
+        if line.contains('\t') {
+            tabs.push(number);
+        }
+
+        let blank = line.trim().is_empty();
+        if blank && previous_blank {
+            blank_lines.push(number);
+        }
+        previous_blank = blank;
+
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        if !blank && indent % 4 != 0 {
+            indentation.push(number);
+        }
+    }
+
+    // An empty module formats to nothing, which is fine.
+    let final_newline =
+        if formatted.is_empty() || formatted.ends_with('\n') && !formatted.ends_with("\n\n") {
+            Vec::new()
+        } else {
+            vec![formatted.lines().count().max(1)]
+        };
+
+    [
+        ("trailing_whitespace", trailing_whitespace),
+        ("tab", tabs),
+        ("final_newline", final_newline),
+        ("blank_lines", blank_lines),
+        ("indentation", indentation),
+    ]
+    .into_iter()
+    .filter(|(_, lines)| !lines.is_empty())
+    .map(|(check, lines)| Violation { check, lines })
+    .collect()
+}
+
+// Only the `"""` that are code count: not those in a comment or quoted in a
+// plain string.
+fn ends_in_block_string(line: &str, mut in_block_string: bool) -> bool {
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        if rest.starts_with(b"\"\"\"") {
+            in_block_string = !in_block_string;
+            i += 3;
+        } else if in_block_string {
+            i += if rest[0] == b'\\' { 2 } else { 1 };
+        } else if rest[0] == b'#' {
+            break;
+        } else if let quote @ (b'"' | b'\'') = rest[0] {
+            i += 1;
+            while i < bytes.len() && bytes[i] != quote {
+                i += if bytes[i] == b'\\' { 2 } else { 1 };
+            }
+            i += 1;
+        } else {
+            i += 1;
+        }
+    }
+    in_block_string
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checks(formatted: &str) -> Vec<(&'static str, Vec<usize>)> {
+        check_hygiene(formatted)
+            .into_iter()
+            .map(|v| (v.check, v.lines))
+            .collect()
+    }
+
+    #[test]
+    fn block_string_lines_are_left_alone() {
+        let formatted = "x =\n    \"\"\"\n  odd indent \n\n\n    \"\"\"\ny = 1 \n";
+        assert_eq!(checks(formatted), [("trailing_whitespace", vec![7])]);
+    }
+
+    #[test]
+    fn triple_quote_in_a_comment_or_plain_string() {
+        let formatted = "# \"\"\" not a block string\nx = 1 \n";
+        assert_eq!(checks(formatted), [("trailing_whitespace", vec![2])]);
+
+        let formatted = "x = \"\\\"\\\"\\\"\"\ny = 1 \n";
+        assert_eq!(checks(formatted), [("trailing_whitespace", vec![2])]);
+    }
+
+    #[test]
+    fn every_check() {
+        let formatted = "x =\n\t1\n\n\ny =\n  2";
+        assert_eq!(
+            checks(formatted),
+            [
+                ("tab", vec![2]),
+                ("final_newline", vec![6]),
+                ("blank_lines", vec![4]),
+                ("indentation", vec![6]),
+            ]
+        );
+    }
+}
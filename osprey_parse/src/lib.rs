@@ -0,0 +1,915 @@
+// What `osprey_parse`'s commands share: the pipeline `parse_one` runs each
+// file through and everything that stores, compares and reports its results.
+// It's a library so that code outside the binary, like fuzz targets, can run
+// exactly that pipeline rather than a copy of it.
+
+use bumpalo::Bump;
+use corpus::CorpusEntry;
+use db::{copy_result, insert_result, load_completed_results};
+use parse_error::ParseError;
+use roc_fmt::annotation::Formattable;
+use roc_fmt::module::fmt_module;
+use roc_fmt::Buf;
+use roc_parse::{
+    ast::{Defs, Expr},
+    header::parse_module_defs,
+    normalize::Normalize,
+    parser::Parser,
+    state::State,
+    test_helpers::parse_loc_with,
+};
+use rusqlite::{Connection, Result};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+use worker::{WorkerLimits, WorkerProcess};
+
+mod ast_diff;
+mod comments;
+pub mod corpus;
+pub mod db;
+mod defs;
+pub mod diff;
+pub mod fuzz_corpus;
+pub mod generate;
+mod hygiene;
+pub mod minimize;
+pub mod parse_error;
+pub mod show;
+pub mod snapshots;
+pub mod stats;
+pub mod worker;
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct ParseData {
+    output: Option<String>,
+    entrypoint: Option<String>,
+    error: Option<String>,
+    error_kind: Option<String>,
+    error_offset: Option<usize>,
+    error_line: Option<usize>,
+    error_column: Option<usize>,
+    error_source_line: Option<String>,
+
+    fmt_output: Option<String>,
+
+    reparse_output: Option<String>,
+    reparse_error: Option<String>,
+    reparse_error_kind: Option<String>,
+    reparse_error_offset: Option<usize>,
+    reparse_error_line: Option<usize>,
+    reparse_error_column: Option<usize>,
+    reparse_error_source_line: Option<String>,
+
+    normalized_output: Option<String>,
+    normalized_reparse_output: Option<String>,
+
+    double_fmt_output: Option<String>,
+
+    fmt_changed: Option<String>,
+    fmt_changed_syntax: Option<bool>,
+    fmt_idempotent: Option<bool>,
+
+    // How repeated formatting ends: `converged`, `cycle`, `diverged` when it
+    // was still changing after --max-fmt-passes, or `reparse_error` when a
+    // later pass no longer parses. Pass sizes are a JSON array of byte
+    // lengths, only kept for files that aren't idempotent.
+    fmt_convergence: Option<String>,
+    fmt_passes_to_converge: Option<i64>,
+    fmt_cycle_length: Option<i64>,
+    fmt_pass_sizes: Option<String>,
+    fmt_grows: Option<bool>,
+
+    // Where the reparsed AST first differs from the original one, when
+    // formatting changed syntax: the node's path from the root, its kind,
+    // and its byte range in the original and in the formatted source
+    divergence_path: Option<String>,
+    divergence_kind: Option<String>,
+    divergence_start: Option<usize>,
+    divergence_end: Option<usize>,
+    divergence_fmt_start: Option<usize>,
+    divergence_fmt_end: Option<usize>,
+
+    // Whether `fmt_output` passes the checks in `hygiene`, and if not, a JSON
+    // array of the failed checks with their line numbers
+    fmt_hygienic: Option<bool>,
+    fmt_hygiene_violations: Option<String>,
+
+    // Whether the formatted output has the same comments in the same order,
+    // and if not, JSON arrays of those it lost, duplicated or moved
+    comments_preserved: Option<bool>,
+    comments_lost: Option<String>,
+    comments_duplicated: Option<String>,
+    comments_moved: Option<String>,
+
+    panic_stage: Option<String>,
+    panic_message: Option<String>,
+    panic_backtrace: Option<String>,
+
+    outcome: Option<String>,
+    outcome_details: Option<String>,
+
+    // Wall time of each stage in microseconds
+    parse_us: Option<i64>,
+    format_us: Option<i64>,
+    reparse_us: Option<i64>,
+    normalize_us: Option<i64>,
+    double_format_us: Option<i64>,
+    parse_bytes_per_sec: Option<f64>,
+
+    // Size of the file's bump arena after each of these stages. The arena is
+    // shared, so these are running totals, and since bumpalo grows by whole
+    // chunks they overstate what was actually used by up to 2x.
+    arena_bytes_parse: Option<i64>,
+    arena_bytes_format: Option<i64>,
+    arena_bytes_reparse: Option<i64>,
+
+    // One per top-level def; stored in `def_results` rather than as columns
+    def_results: Vec<defs::DefResult>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct Output<'a> {
+    header: roc_parse::ast::Module<'a>,
+    module_defs: Defs<'a>,
+}
+
+impl<'a> Normalize<'a> for Output<'a> {
+    fn remove_spaces(&self, arena: &'a Bump) -> Self {
+        Output {
+            header: self.header.remove_spaces(arena),
+            module_defs: self.module_defs.remove_spaces(arena),
+        }
+    }
+}
+
+fn parse_module<'a>(input: &'a str, arena: &'a Bump) -> Result<Output<'a>, ParseError> {
+    let state = State::new(input.as_bytes());
+    let min_indent = 0;
+    let (_, header, state) = roc_parse::module::header()
+        .parse(arena, state.clone(), min_indent)
+        .map_err(|e| ParseError::new(format!("{:?}", e), input))?;
+
+    let (header, defs) = header.upgrade_header_imports(arena);
+    let module_defs = parse_module_defs(arena, state, defs)
+        .map_err(|e| ParseError::new(format!("{:?}", e), input))?;
+
+    Ok(Output {
+        header,
+        module_defs,
+    })
+}
+
+fn format_module(output: &Output, arena: &Bump) -> String {
+    let mut buf = Buf::new_in(arena);
+    fmt_module(&mut buf, &output.header);
+    output.module_defs.format(&mut buf, 0);
+    buf.fmt_end_of_file();
+    buf.as_str().to_string()
+}
+
+// Scripts, snippets and files with headers roc no longer accepts don't parse
+// as modules, but may still parse as bare defs or a single expression. These
+// are tried in order, like `osprey_zulip::parse_roc_code` does.
+#[derive(Clone, Copy, Debug)]
+enum Entrypoint {
+    Module,
+    Defs,
+    Expr,
+}
+
+impl Entrypoint {
+    const ALL: [Entrypoint; 3] = [Entrypoint::Module, Entrypoint::Defs, Entrypoint::Expr];
+
+    fn name(self) -> &'static str {
+        match self {
+            Entrypoint::Module => "module",
+            Entrypoint::Defs => "defs",
+            Entrypoint::Expr => "expr",
+        }
+    }
+}
+
+// A module's header is far bigger than the other variants, so it's kept in
+// the arena with the rest of the tree.
+pub enum Parsed<'a> {
+    Module(&'a Output<'a>),
+    Defs(Defs<'a>),
+    Expr(Expr<'a>),
+}
+
+// Delegates, so the `output` of modules reads the same as before headerless
+// files were accepted.
+impl<'a> std::fmt::Debug for Parsed<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Parsed::Module(output) => output.fmt(f),
+            Parsed::Defs(defs) => defs.fmt(f),
+            Parsed::Expr(expr) => expr.fmt(f),
+        }
+    }
+}
+
+impl<'a> Normalize<'a> for Parsed<'a> {
+    fn remove_spaces(&self, arena: &'a Bump) -> Self {
+        match self {
+            Parsed::Module(output) => Parsed::Module(arena.alloc(output.remove_spaces(arena))),
+            Parsed::Defs(defs) => Parsed::Defs(defs.remove_spaces(arena)),
+            Parsed::Expr(expr) => Parsed::Expr(expr.remove_spaces(arena)),
+        }
+    }
+}
+
+impl<'a> Parsed<'a> {
+    fn entrypoint(&self) -> Entrypoint {
+        match self {
+            Parsed::Module(_) => Entrypoint::Module,
+            Parsed::Defs(_) => Entrypoint::Defs,
+            Parsed::Expr(_) => Entrypoint::Expr,
+        }
+    }
+}
+
+fn parse_with<'a>(
+    entrypoint: Entrypoint,
+    input: &'a str,
+    arena: &'a Bump,
+) -> Result<Parsed<'a>, ParseError> {
+    match entrypoint {
+        Entrypoint::Module => {
+            parse_module(input, arena).map(|output| Parsed::Module(arena.alloc(output)))
+        }
+        Entrypoint::Defs => parse_module_defs(arena, State::new(input.as_bytes()), Defs::default())
+            .map(Parsed::Defs)
+            .map_err(|e| ParseError::new(format!("{:?}", e), input)),
+        Entrypoint::Expr => parse_loc_with(arena, input)
+            .map(|loc_expr| Parsed::Expr(loc_expr.value))
+            .map_err(|e| ParseError::new(format!("{:?}", e.problem), input)),
+    }
+}
+
+// When nothing matches, the module error is the one reported: it's what
+// `error` has always held, and for a file that was meant to be a module it's
+// the relevant one.
+pub fn parse_any<'a>(input: &'a str, arena: &'a Bump) -> Result<Parsed<'a>, ParseError> {
+    let mut module_error = None;
+    for entrypoint in Entrypoint::ALL {
+        match parse_with(entrypoint, input, arena) {
+            Ok(parsed) => return Ok(parsed),
+            Err(e) => {
+                module_error.get_or_insert(e);
+            }
+        }
+    }
+    Err(module_error.unwrap())
+}
+
+// The formatter's buffer goes in the caller's arena, so that its size is
+// part of what `run_stages` records for the format stage.
+fn format_parsed(parsed: &Parsed, arena: &Bump) -> String {
+    match parsed {
+        Parsed::Module(output) => format_module(output, arena),
+        Parsed::Defs(defs) => {
+            let mut buf = Buf::new_in(arena);
+            defs.format(&mut buf, 0);
+            buf.fmt_end_of_file();
+            buf.as_str().to_string()
+        }
+        Parsed::Expr(expr) => {
+            let mut buf = Buf::new_in(arena);
+            expr.format(&mut buf, 0);
+            buf.fmt_end_of_file();
+            buf.as_str().to_string()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Stage {
+    Parse,
+    Format,
+    Reparse,
+    Normalize,
+    DoubleFormat,
+    Converge,
+    Defs,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Parse => "parse",
+            Stage::Format => "format",
+            Stage::Reparse => "reparse",
+            Stage::Normalize => "normalize",
+            Stage::DoubleFormat => "double_format",
+            Stage::Converge => "converge",
+            Stage::Defs => "defs",
+        }
+    }
+}
+
+// Cleared by `minimize`, which provokes the same panic thousands of times and
+// has no use for the message or the (slow to capture) backtrace.
+pub static REPORT_PANICS: AtomicBool = AtomicBool::new(true);
+
+// How many times a non-idempotent file is formatted, counting the first two,
+// before it's given up on as not converging. Set by `parse --max-fmt-passes`.
+pub static MAX_FMT_PASSES: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_FMT_PASSES);
+const DEFAULT_MAX_FMT_PASSES: usize = 10;
+
+thread_local! {
+    static LAST_PANIC_BACKTRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+    // Set while `record_stages` runs, i.e. while a panic is one of the file's.
+    static IN_STAGES: Cell<bool> = const { Cell::new(false) };
+}
+
+// Installed once at startup. The default hook would print every panic in a
+// parser or formatter stage (and its backtrace) to stderr in the middle of the
+// run; for those this one prints nothing and only stashes the backtrace, so
+// `parse_one` can store it next to the message for the file that caused it.
+// Any other panic is a bug in osprey itself and goes to the default hook.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if worker::memory_limit_hit() {
+            return;
+        }
+        if !IN_STAGES.with(Cell::get) {
+            default_hook(info);
+            return;
+        }
+        if !REPORT_PANICS.load(Ordering::Relaxed) {
+            return;
+        }
+        let backtrace = Backtrace::force_capture().to_string();
+        LAST_PANIC_BACKTRACE.with(|b| *b.borrow_mut() = Some(backtrace));
+    }));
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "<non-string panic payload>".to_string()
+    }
+}
+
+pub fn parse_one(input: &str) -> ParseData {
+    record_stages(|result, stage| run_stages(input, result, stage))
+}
+
+// Runs `stages`, recording a panic in any of them against the stage it had
+// reached.
+fn record_stages(stages: impl FnOnce(&mut ParseData, &mut Stage)) -> ParseData {
+    let mut result = ParseData {
+        outcome: Some(worker::OUTCOME_COMPLETED.to_string()),
+        ..ParseData::default()
+    };
+    let mut stage = Stage::Parse;
+
+    let in_stages = IN_STAGES.with(|flag| flag.replace(true));
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| stages(&mut result, &mut stage)));
+    IN_STAGES.with(|flag| flag.set(in_stages));
+
+    if let Err(payload) = outcome {
+        result.panic_stage = Some(stage.name().to_string());
+        result.panic_message = Some(panic_message(&*payload));
+        result.panic_backtrace = take_panic_backtrace();
+    }
+
+    result
+}
+
+// What the panic hook stashed for the latest panic on this thread.
+fn take_panic_backtrace() -> Option<String> {
+    LAST_PANIC_BACKTRACE.with(|b| b.borrow_mut().take())
+}
+
+// Everything recorded before a panic is kept, so `stage` is advanced just
+// before each step that can blow up.
+fn run_stages(input: &str, result: &mut ParseData, stage: &mut Stage) {
+    let arena = bumpalo::Bump::new();
+    *stage = Stage::Parse;
+    let (parsed, parse_us) = timed(|| parse_any(input, &arena));
+    result.parse_us = Some(parse_us);
+    result.arena_bytes_parse = Some(arena.allocated_bytes() as i64);
+    if parse_us > 0 {
+        result.parse_bytes_per_sec = Some(input.len() as f64 * 1e6 / parse_us as f64);
+    }
+    let output = match parsed {
+        Ok(o) => o,
+        Err(e) => {
+            result.error = Some(e.debug);
+            result.error_kind = Some(e.kind);
+            result.error_offset = e.offset;
+            result.error_line = e.line;
+            result.error_column = e.column;
+            result.error_source_line = e.source_line;
+            return;
+        }
+    };
+
+    result.output = Some(format!("{:#?}", output));
+    result.entrypoint = Some(output.entrypoint().name().to_string());
+
+    run_fmt_stages(input, &output, &arena, result, stage);
+
+    // Last, so that the file's own stages have all run (and recorded any
+    // panic of theirs) whatever happens to its defs.
+    *stage = Stage::Defs;
+    result.def_results = defs::check_defs(input, &output);
+}
+
+// The stages after parsing, up to the first one that fails.
+fn run_fmt_stages(
+    input: &str,
+    output: &Parsed,
+    arena: &Bump,
+    result: &mut ParseData,
+    stage: &mut Stage,
+) {
+    *stage = Stage::Format;
+    let (formatted, format_us) = timed(|| format_parsed(output, arena));
+    result.format_us = Some(format_us);
+    result.arena_bytes_format = Some(arena.allocated_bytes() as i64);
+
+    result.fmt_output = Some(formatted.clone());
+    let violations = hygiene::check_hygiene(&formatted);
+    result.fmt_hygienic = Some(violations.is_empty());
+    if !violations.is_empty() {
+        result.fmt_hygiene_violations = Some(serde_json::to_string(&violations).unwrap());
+    }
+    result.fmt_changed = Some(format!("{:#?}", formatted != input));
+    record_comments(
+        &comments::comments(input),
+        &comments::comments(&formatted),
+        result,
+    );
+
+    *stage = Stage::Reparse;
+    let (reparsed, reparse_us) =
+        timed(|| parse_with(output.entrypoint(), formatted.as_str(), arena));
+    result.reparse_us = Some(reparse_us);
+    result.arena_bytes_reparse = Some(arena.allocated_bytes() as i64);
+    let reparsed_output = match reparsed {
+        Ok(o) => o,
+        Err(e) => {
+            result.reparse_error = Some(e.debug);
+            result.reparse_error_kind = Some(e.kind);
+            result.reparse_error_offset = e.offset;
+            result.reparse_error_line = e.line;
+            result.reparse_error_column = e.column;
+            result.reparse_error_source_line = e.source_line;
+            return;
+        }
+    };
+
+    result.reparse_output = Some(format!("{:#?}", reparsed_output));
+
+    *stage = Stage::Normalize;
+    let ((output_normalized, reparsed_output_normalized), normalize_us) = timed(|| {
+        (
+            output.remove_spaces(arena),
+            reparsed_output.remove_spaces(arena),
+        )
+    });
+    result.normalize_us = Some(normalize_us);
+
+    result.normalized_output = Some(format!("{:#?}", output_normalized));
+    result.normalized_reparse_output = Some(format!("{:#?}", reparsed_output_normalized));
+
+    result.fmt_changed_syntax = Some(result.normalized_output != result.normalized_reparse_output);
+    if result.fmt_changed_syntax == Some(true) {
+        record_divergence(result);
+    }
+
+    *stage = Stage::DoubleFormat;
+    let (double_formatted, double_format_us) = timed(|| format_parsed(&reparsed_output, arena));
+    result.double_format_us = Some(double_format_us);
+
+    result.fmt_idempotent = Some(formatted == double_formatted);
+    result.double_fmt_output = Some(double_formatted.clone());
+
+    *stage = Stage::Converge;
+    track_convergence(
+        output.entrypoint(),
+        vec![formatted, double_formatted],
+        result,
+    );
+}
+
+// `passes` starts out as the first and second formatting. Each further pass
+// gets a fresh arena, so a file that grows every pass can't exhaust memory
+// by accumulating all of them.
+fn track_convergence(entrypoint: Entrypoint, mut passes: Vec<String>, result: &mut ParseData) {
+    let max_passes = MAX_FMT_PASSES.load(Ordering::Relaxed).max(2);
+    let convergence = loop {
+        let last = passes.len() - 1;
+        if passes[last] == passes[last - 1] {
+            result.fmt_passes_to_converge = Some(last as i64);
+            break "converged";
+        }
+        if let Some(first) = passes[..last - 1].iter().position(|p| *p == passes[last]) {
+            result.fmt_cycle_length = Some((last - first) as i64);
+            break "cycle";
+        }
+        if passes.len() >= max_passes {
+            break "diverged";
+        }
+        let arena = Bump::new();
+        match parse_with(entrypoint, &passes[last], &arena) {
+            Ok(parsed) => passes.push(format_parsed(&parsed, &arena)),
+            Err(_) => break "reparse_error",
+        }
+    };
+    result.fmt_convergence = Some(convergence.to_string());
+
+    let sizes: Vec<usize> = passes.iter().map(String::len).collect();
+    result.fmt_grows = Some(sizes.windows(2).all(|pair| pair[0] < pair[1]));
+    if result.fmt_idempotent == Some(false) {
+        result.fmt_pass_sizes = Some(serde_json::to_string(&sizes).unwrap());
+    }
+}
+
+fn record_comments(input: &[String], formatted: &[String], result: &mut ParseData) {
+    let check = comments::check_comments(input, formatted);
+    let list = |comments: &Vec<String>| {
+        (!comments.is_empty()).then(|| serde_json::to_string(comments).unwrap())
+    };
+    result.comments_preserved = Some(check.preserved());
+    result.comments_lost = list(&check.lost);
+    result.comments_duplicated = list(&check.duplicated);
+    result.comments_moved = list(&check.moved);
+}
+
+fn record_divergence(result: &mut ParseData) {
+    let (Some(normalized), Some(normalized_reparse), Some(output), Some(reparse_output)) = (
+        &result.normalized_output,
+        &result.normalized_reparse_output,
+        &result.output,
+        &result.reparse_output,
+    ) else {
+        return;
+    };
+    if let Some(divergence) =
+        ast_diff::first_divergence(normalized, normalized_reparse, output, reparse_output)
+    {
+        result.divergence_path = Some(divergence.path);
+        result.divergence_kind = Some(divergence.kind);
+        result.divergence_start = divergence.start;
+        result.divergence_end = divergence.end;
+        result.divergence_fmt_start = divergence.fmt_start;
+        result.divergence_fmt_end = divergence.fmt_end;
+    }
+}
+
+// Only the roc_parse/roc_fmt call itself is timed, not the Debug printing of
+// its output that follows.
+fn timed<T>(f: impl FnOnce() -> T) -> (T, i64) {
+    let start = Instant::now();
+    let value = f();
+    (value, start.elapsed().as_micros() as i64)
+}
+
+pub struct CorpusFile {
+    pub index: usize,
+    pub repo_url: String,
+    pub file_path: String,
+    pub file_hash: Option<String>,
+    pub contents: String,
+    // Set when an earlier row has results for identical contents
+    pub reuse_id: Option<i64>,
+    // Set when an earlier file in this run has identical contents
+    pub duplicate: bool,
+}
+
+// Identifies the roc_parse/roc_fmt sources this binary was built from; see
+// build.rs. Results are only reused between runs with the same id.
+const PARSER_BUILD_ID: &str = env!("PARSER_BUILD_ID");
+
+// `git describe` of the roc checkout the parser was built from, recorded with
+// each run.
+const ROC_REVISION: &str = env!("ROC_REVISION");
+
+// `first_rows` maps each file hash to the row that was written for its first
+// occurrence in this run, which duplicates are copied from.
+pub fn insert_batch(
+    conn: &mut Connection,
+    run_id: i64,
+    batch: &mut Vec<(CorpusFile, ParseData)>,
+    first_rows: &mut HashMap<String, i64>,
+) -> Result<()> {
+    let transaction = conn.transaction()?;
+    for (file, result) in batch.iter() {
+        let first_row = file
+            .file_hash
+            .as_ref()
+            .and_then(|hash| first_rows.get(hash));
+        let row_id = match (file.duplicate, first_row, file.reuse_id) {
+            (true, Some(&id), _) => copy_result(&transaction, run_id, file, id, Some(id))?,
+            (_, _, Some(id)) => copy_result(&transaction, run_id, file, id, None)?,
+            _ => insert_result(&transaction, run_id, file, result)?,
+        };
+        if let (false, Some(hash)) = (file.duplicate, &file.file_hash) {
+            first_rows.insert(hash.clone(), row_id);
+        }
+    }
+    transaction.commit()?;
+    batch.clear();
+    Ok(())
+}
+
+// How many files, per job, may be handed out past the oldest one whose result
+// hasn't been written yet. Without a limit, one slow file lets the results of
+// the whole rest of the corpus pile up in `write_results`.
+const WRITE_WINDOW_PER_JOB: usize = 64;
+
+// Shared by `parse_corpus`, which waits on it before handing out each file,
+// and `write_results`, which moves it along as files are written.
+struct WriteWindow {
+    size: usize,
+    progress: Mutex<WriteProgress>,
+    advanced: Condvar,
+}
+
+#[derive(Default)]
+struct WriteProgress {
+    next_index: usize,
+    // Set when the writer stops, so nobody waits on it forever if it failed
+    closed: bool,
+}
+
+impl WriteWindow {
+    fn new(size: usize) -> WriteWindow {
+        WriteWindow {
+            size,
+            progress: Mutex::new(WriteProgress::default()),
+            advanced: Condvar::new(),
+        }
+    }
+
+    fn wait_for(&self, index: usize) {
+        let mut progress = self.progress.lock().unwrap();
+        while !progress.closed && index >= progress.next_index + self.size {
+            progress = self.advanced.wait(progress).unwrap();
+        }
+    }
+
+    fn advance(&self, next_index: usize) {
+        self.progress.lock().unwrap().next_index = next_index;
+        self.advanced.notify_all();
+    }
+
+    fn close(&self) {
+        self.progress.lock().unwrap().closed = true;
+        self.advanced.notify_all();
+    }
+}
+
+// Why `parse_corpus` stopped before every file had a row in the run.
+pub type RunError = Box<dyn std::error::Error + Send + Sync>;
+
+// Results can arrive from the workers in any order, so hold them back until
+// every earlier file has been written. That keeps row ids (and therefore the
+// whole results db) identical to a single-threaded run. `window` keeps the
+// number held back bounded.
+fn write_results(
+    conn: &mut Connection,
+    run_id: i64,
+    results: Receiver<(CorpusFile, ParseData)>,
+    expected: usize,
+    batch_size: usize,
+    window: &WriteWindow,
+) -> Result<(), RunError> {
+    let mut pending = BTreeMap::new();
+    let mut next_index = 0;
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut first_rows = HashMap::new();
+
+    for (file, result) in results {
+        pending.insert(file.index, (file, result));
+
+        let before = next_index;
+        while let Some(entry) = pending.remove(&next_index) {
+            batch.push(entry);
+            next_index += 1;
+
+            if batch.len() >= batch_size {
+                insert_batch(conn, run_id, &mut batch, &mut first_rows)?;
+            }
+        }
+        if next_index != before {
+            window.advance(next_index);
+        }
+    }
+
+    insert_batch(conn, run_id, &mut batch, &mut first_rows)?;
+
+    // The channel also closes when a worker dies, which would otherwise look
+    // just like a finished run with some rows missing.
+    if next_index != expected || !pending.is_empty() {
+        return Err(format!(
+            "wrote results for {} of {} files ({} more stuck behind a missing one)",
+            next_index,
+            expected,
+            pending.len()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+pub struct ParseOptions {
+    pub jobs: usize,
+    pub batch_size: usize,
+    pub isolate: bool,
+    pub limits: WorkerLimits,
+    pub incremental: bool,
+}
+
+pub fn parse_corpus(
+    conn_corpus: &Connection,
+    entries: &[CorpusEntry],
+    conn_results: &mut Connection,
+    run_id: i64,
+    options: &ParseOptions,
+) -> Result<(), RunError> {
+    let ParseOptions {
+        jobs,
+        batch_size,
+        isolate,
+        ref limits,
+        incremental,
+    } = *options;
+
+    let completed = if incremental {
+        load_completed_results(conn_results)?
+    } else {
+        HashMap::new()
+    };
+
+    let mut stmt =
+        conn_corpus.prepare("SELECT file_hash, file_contents FROM roc_files WHERE id = ?1")?;
+    let file_contents_iter = entries.iter().map(|entry| {
+        stmt.query_row([entry.id], |row| {
+            let file_hash = row.get::<_, Option<String>>(0)?;
+            let file_contents = row.get::<_, String>(1)?;
+
+            Ok((
+                entry.repo_url.clone(),
+                entry.file_path.clone(),
+                file_hash,
+                file_contents,
+            ))
+        })
+    });
+
+    let (file_tx, file_rx) = mpsc::sync_channel::<CorpusFile>(jobs * 4);
+    let file_rx = Arc::new(Mutex::new(file_rx));
+    let (result_tx, result_rx) = mpsc::channel();
+
+    let window = WriteWindow::new(jobs * WRITE_WINDOW_PER_JOB);
+
+    thread::scope(|s| {
+        let window = &window;
+        let writer = s.spawn(move || {
+            let written = write_results(
+                conn_results,
+                run_id,
+                result_rx,
+                entries.len(),
+                batch_size,
+                window,
+            );
+            window.close();
+            written
+        });
+
+        let mut workers = Vec::with_capacity(jobs);
+        for _ in 0..jobs {
+            let file_rx = Arc::clone(&file_rx);
+            let result_tx = result_tx.clone();
+            let mut process = isolate.then(|| WorkerProcess::new(limits.clone()));
+
+            // In isolated mode the stack size is applied inside the child
+            // instead; these threads only shuttle data back and forth.
+            let mut builder = thread::Builder::new();
+            if let (false, Some(stack_size)) = (isolate, limits.stack_size) {
+                builder = builder.stack_size(stack_size);
+            }
+
+            let worker = builder
+                .spawn_scoped(s, move || loop {
+                    let next = file_rx.lock().unwrap().recv();
+                    let Ok(file) = next else {
+                        break;
+                    };
+
+                    println!("Parsing file: {} {}", file.repo_url, file.file_path);
+                    let result = match &mut process {
+                        Some(process) => process.parse(&file.contents),
+                        None => parse_one(&file.contents),
+                    };
+
+                    if result_tx.send((file, result)).is_err() {
+                        break;
+                    }
+                })
+                .expect("failed to spawn parse thread");
+            workers.push(worker);
+        }
+        drop(file_rx);
+
+        let mut read_result = Ok(());
+        let mut reused = 0;
+        let mut duplicates = 0;
+        let mut seen_hashes = HashSet::new();
+        for (index, row) in file_contents_iter.enumerate() {
+            let (repo_url, file_path, file_hash, contents) = match row {
+                Ok(row) => row,
+                Err(e) => {
+                    read_result = Err(e);
+                    break;
+                }
+            };
+
+            // Every run gets a row for every file, so unchanged contents are
+            // copied over from an earlier run rather than skipped, and forks
+            // and copies of a file are parsed once and copied to the rest.
+            let duplicate = file_hash
+                .as_ref()
+                .is_some_and(|hash| !seen_hashes.insert(hash.clone()));
+            let reuse_id = file_hash
+                .as_ref()
+                .and_then(|hash| completed.get(hash).copied());
+            if duplicate {
+                duplicates += 1;
+            } else if reuse_id.is_some() {
+                reused += 1;
+            }
+
+            let file = CorpusFile {
+                index,
+                repo_url,
+                file_path,
+                file_hash,
+                contents,
+                reuse_id,
+                duplicate,
+            };
+
+            // The workers only hang up early if the writer failed, in which
+            // case the writer's error is the one worth reporting.
+            window.wait_for(index);
+            let sent = if duplicate || reuse_id.is_some() {
+                result_tx.send((file, ParseData::default())).map_err(|_| ())
+            } else {
+                file_tx.send(file).map_err(|_| ())
+            };
+            if sent.is_err() {
+                break;
+            }
+        }
+        drop(file_tx);
+        drop(result_tx);
+
+        println!(
+            "{} files had the same contents as an earlier file in this run",
+            duplicates
+        );
+        if incremental {
+            println!(
+                "Reused results for {} files already parsed with parser build {}",
+                reused, PARSER_BUILD_ID
+            );
+        }
+
+        let mut worker_panic = None;
+        for worker in workers {
+            if let Err(payload) = worker.join() {
+                worker_panic = Some(panic_message(&*payload));
+            }
+        }
+        let written = writer.join().unwrap();
+
+        // A reading error or a dead worker also leaves the writer short of
+        // files, so report those first; they're the actual cause.
+        read_result?;
+        if let Some(message) = worker_panic {
+            return Err(format!("a parse thread panicked: {}", message).into());
+        }
+        written
+    })
+}
@@ -1,351 +1,825 @@
-use bumpalo::Bump;
-use roc_fmt::annotation::Formattable;
-use roc_fmt::Buf;
-use roc_parse::{
-    ast::Defs, header::parse_module_defs, normalize::Normalize, parser::Parser, state::State,
+use osprey_parse::corpus::{CorpusFilter, CorpusSource};
+use osprey_parse::db::{
+    create_run, ensure_results_schema, finish_run, load_contents, load_contents_by_id, load_result,
+    load_result_by_id, load_row_key, previous_run, resolve_run, RunInfo,
 };
-use rusqlite::OptionalExtension;
-use rusqlite::{params, Connection, Result};
-
-struct ParseData {
-    output: Option<String>,
-    error: Option<String>,
-
-    fmt_output: Option<String>,
-
-    reparse_output: Option<String>,
-    reparse_error: Option<String>,
-
-    normalized_output: Option<String>,
-    normalized_reparse_output: Option<String>,
-
-    double_fmt_output: Option<String>,
-
-    fmt_changed: Option<String>,
-    fmt_changed_syntax: Option<bool>,
-    fmt_idempotent: Option<bool>,
-}
-
-#[allow(dead_code)]
-#[derive(Debug)]
-struct Output<'a> {
-    header: roc_parse::ast::Module<'a>,
-    module_defs: Defs<'a>,
-}
+use osprey_parse::diff::{diff_runs, DiffOptions, OutputFormat};
+use osprey_parse::minimize::Failure;
+use osprey_parse::snapshots::{export_snapshots, ExportOptions, FAILURE_KINDS};
+use osprey_parse::stats::{compute_stats, report_stats};
+use osprey_parse::worker::{
+    LimitedAlloc, WorkerLimits, WorkerProcess, OUTCOME_OOM, OUTCOME_TIMEOUT,
+};
+use osprey_parse::{
+    fuzz_corpus, generate, insert_batch, install_panic_hook, minimize, parse_corpus, parse_one,
+    show, worker, CorpusFile, ParseData, ParseOptions, MAX_FMT_PASSES, REPORT_PANICS,
+};
+use rusqlite::{Connection, Result};
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
 
-impl<'a> Normalize<'a> for Output<'a> {
-    fn remove_spaces(&self, arena: &'a Bump) -> Self {
-        Output {
-            header: self.header.remove_spaces(arena),
-            module_defs: self.module_defs.remove_spaces(arena),
+#[global_allocator]
+static GLOBAL: LimitedAlloc = LimitedAlloc;
+
+// `recorded` is the row being minimized, if it came from a results db. Its
+// failure is the one kept, rather than whatever the current parser does with
+// the file first.
+fn run_minimize(
+    source: &str,
+    recorded: Option<&ParseData>,
+    out: Option<&str>,
+    limits: WorkerLimits,
+) {
+    let recorded_target = recorded.map(|recorded| {
+        minimize::failures(recorded)
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| {
+                eprintln!("This row didn't fail when it was parsed; nothing to minimize");
+                std::process::exit(1);
+            })
+    });
+
+    // A hang, stack overflow or OOM only happens again, and can only be
+    // survived, in a worker with the limits the row was parsed under.
+    if let Some(Failure::Outcome(outcome)) = &recorded_target {
+        let missing = match outcome.as_str() {
+            OUTCOME_TIMEOUT if limits.timeout.is_none() => Some("--timeout-ms"),
+            OUTCOME_OOM if limits.memory_limit.is_none() => Some("--memory-limit-mb"),
+            _ => None,
+        };
+        if let Some(flag) = missing {
+            eprintln!(
+                "This row's outcome is {}; pass {} with the limit it was parsed under",
+                outcome, flag
+            );
+            std::process::exit(2);
         }
     }
-}
-
-fn parse_module<'a>(input: &'a str, arena: &'a Bump) -> Result<Output<'a>, String> {
-    let state = State::new(input.as_bytes());
-    let min_indent = 0;
-    let (_, header, state) = roc_parse::module::header()
-        .parse(arena, state.clone(), min_indent)
-        .map_err(|e| format!("{:?}", e))?;
-
-    let (header, defs) = header.upgrade_header_imports(arena);
-    let module_defs = parse_module_defs(arena, state, defs).map_err(|e| format!("{:?}", e))?;
-
-    Ok(Output {
-        header,
-        module_defs,
-    })
-}
-
-fn format_module(output: &Output) -> String {
-    let arena = Bump::new();
-    let mut buf = Buf::new_in(&arena);
-    fmt_module(&mut buf, &output.header);
-    output.module_defs.format(&mut buf, 0);
-    buf.fmt_end_of_file();
-    buf.as_str().to_string()
-}
-
-fn parse_one(input: &str) -> ParseData {
-    let mut result = ParseData {
-        output: None,
-        error: None,
-        fmt_output: None,
-        reparse_output: None,
-        reparse_error: None,
-
-        normalized_output: None,
-        normalized_reparse_output: None,
-
-        double_fmt_output: None,
-
-        fmt_changed: None,
-        fmt_changed_syntax: None,
-        fmt_idempotent: None,
+    let isolate = matches!(recorded_target, Some(Failure::Outcome(_)))
+        || limits.timeout.is_some()
+        || limits.stack_size.is_some()
+        || limits.memory_limit.is_some();
+
+    let mut process = isolate.then(|| WorkerProcess::new(limits));
+    let mut run = |input: &str| match &mut process {
+        Some(process) => process.parse(input),
+        None => parse_one(input),
     };
 
-    let arena = bumpalo::Bump::new();
-    let output = match parse_module(input, &arena) {
-        Ok(o) => o,
-        Err(e) => {
-            result.error = Some(e);
-            return result;
+    let found = minimize::failures(&run(source));
+    let target = match recorded_target {
+        Some(target) if found.contains(&target) => target,
+        Some(target) => {
+            eprintln!(
+                "The row's failure ({}) doesn't happen again with the current parser and limits",
+                target.describe()
+            );
+            std::process::exit(1);
         }
+        None => found.into_iter().next().unwrap_or_else(|| {
+            eprintln!("This file doesn't fail with the current parser; nothing to minimize");
+            std::process::exit(1);
+        }),
     };
-
-    result.output = Some(format!("{:#?}", output));
-
-    let formatted = format_module(&output);
-
-    result.fmt_output = Some(formatted.clone());
-    result.fmt_changed = Some(format!("{:#?}", formatted != input));
-
-    let reparsed_output = match parse_module(formatted.as_str(), &arena) {
-        Ok(o) => o,
-        Err(e) => {
-            result.reparse_error = Some(e);
-            return result;
+    eprintln!("Minimizing: {}", target.describe());
+
+    REPORT_PANICS.store(false, Ordering::Relaxed);
+    let minimized = minimize::minimize(source, &target, run);
+    REPORT_PANICS.store(true, Ordering::Relaxed);
+
+    eprintln!(
+        "Reduced {} bytes to {} bytes in {} attempts",
+        source.len(),
+        minimized.source.len(),
+        minimized.attempts
+    );
+
+    match out {
+        Some(out) => {
+            if let Err(e) = std::fs::write(out, &minimized.source) {
+                eprintln!("Failed to write {}: {}", out, e);
+                std::process::exit(1);
+            }
         }
-    };
-
-    result.reparse_output = Some(format!("{:#?}", reparsed_output));
-
-    let output_normalized = output.remove_spaces(&arena);
-    let reparsed_output_normalized = reparsed_output.remove_spaces(&arena);
+        None => print!("{}", minimized.source),
+    }
+}
 
-    result.normalized_output = Some(format!("{:#?}", output_normalized));
-    result.normalized_reparse_output = Some(format!("{:#?}", reparsed_output_normalized));
+struct GenerateOptions {
+    seed: u64,
+    count: u64,
+    budget: usize,
+}
 
-    result.fmt_changed_syntax = Some(result.normalized_output != result.normalized_reparse_output);
+// Programs are checked one after another, so that the report comes out in
+// seed order; they're small enough for that to be quick. Returns how many
+// had problems.
+fn run_generate(
+    options: &GenerateOptions,
+    mut results: Option<(&mut Connection, i64)>,
+) -> Result<usize> {
+    let mut batch = Vec::new();
+    let mut first_rows = HashMap::new();
+    let mut failed = 0;
+
+    let seeds = options.seed..options.seed.saturating_add(options.count);
+    for (index, seed) in seeds.enumerate() {
+        let generate::Checked {
+            source,
+            result,
+            problems,
+        } = generate::check(seed, options.budget);
+        if !problems.is_empty() {
+            failed += 1;
+            println!("seed {}: {}", seed, problems.join(", "));
+        }
 
-    let double_formatted = format_module(&reparsed_output);
+        if let Some((conn, run_id)) = &mut results {
+            let file = CorpusFile {
+                index,
+                repo_url: generate::REPO.to_string(),
+                file_path: format!("seed-{}.roc", seed),
+                file_hash: None,
+                contents: source,
+                reuse_id: None,
+                duplicate: false,
+            };
+            batch.push((file, result));
+            if batch.len() >= 1000 {
+                insert_batch(conn, *run_id, &mut batch, &mut first_rows)?;
+            }
+        }
+    }
+    if let Some((conn, run_id)) = results {
+        insert_batch(conn, run_id, &mut batch, &mut first_rows)?;
+    }
 
-    result.fmt_idempotent = Some(formatted == double_formatted);
-    result.double_fmt_output = Some(double_formatted);
+    Ok(failed)
+}
 
-    result
+fn find_run(conn: &Connection, results_db: &str, run: Option<&str>) -> Result<i64> {
+    match resolve_run(conn, run)? {
+        Some(run_id) => Ok(run_id),
+        None => {
+            eprintln!("No run {} in {}", run.unwrap_or("at all"), results_db);
+            std::process::exit(1);
+        }
+    }
 }
 
-use structopt::StructOpt;
 #[derive(StructOpt, Debug)]
 #[structopt(name = "roc_parser")]
 enum Opt {
     #[structopt(name = "parse")]
     Parse {
-        #[structopt(short, long)]
-        corpus_db: String,
+        #[structopt(flatten)]
+        source: CorpusSource,
         #[structopt(short, long)]
         results_db: String,
+        /// Number of files to parse in parallel (defaults to the number of cores)
+        #[structopt(short, long)]
+        jobs: Option<usize>,
+        /// Number of results to insert per transaction
+        #[structopt(long, default_value = "1000")]
+        batch_size: usize,
+        /// Parse each file in a child worker process, so that hangs, stack
+        /// overflows and aborts only lose that one file. Implied by
+        /// --timeout-ms and --memory-limit-mb.
+        #[structopt(long)]
+        isolate: bool,
+        /// Kill the worker and record a `timeout` outcome after this long
+        #[structopt(long)]
+        timeout_ms: Option<u64>,
+        /// Stack size for the threads that run the parser
+        #[structopt(long)]
+        stack_size_mb: Option<usize>,
+        /// Heap ceiling for each worker process; files that exceed it are
+        /// recorded with an `oom` outcome
+        #[structopt(long)]
+        memory_limit_mb: Option<usize>,
+        /// Copy results for files whose contents were already parsed by this
+        /// parser build, instead of parsing them again. Files that timed out,
+        /// crashed or aren't idempotent are always parsed again
+        #[structopt(long)]
+        incremental: bool,
+        /// Name for this run, for selecting it later with `diff --run-a/--run-b`
+        #[structopt(long)]
+        run_name: Option<String>,
+        /// Keep reformatting files that aren't idempotent up to this many
+        /// passes in total, to see whether they converge
+        #[structopt(long, default_value = "10")]
+        max_fmt_passes: usize,
+        #[structopt(flatten)]
+        filter: CorpusFilter,
     },
     #[structopt(name = "diff")]
     Diff {
+        #[structopt(flatten)]
+        source: CorpusSource,
+        /// Results db holding both runs; shorthand for passing it as both
+        /// --results-db-a and --results-db-b
         #[structopt(short, long)]
-        corpus_db: String,
+        results_db: Option<String>,
         #[structopt(short = "a", long)]
-        results_db_a: String,
+        results_db_a: Option<String>,
         #[structopt(short = "b", long)]
-        results_db_b: String,
+        results_db_b: Option<String>,
+        /// Run to diff from, by name or id (defaults to the run before run B
+        /// when both sides are the same db, and to the latest run otherwise)
+        #[structopt(long)]
+        run_a: Option<String>,
+        /// Run to diff to, by name or id (defaults to the latest run)
+        #[structopt(long)]
+        run_b: Option<String>,
+        /// `text`, or `json` for one JSON object per changed file
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+        /// Print unified diffs of the text fields that changed
+        #[structopt(short = "d", long)]
+        show_diff: bool,
+        /// Lines of context around each change
+        #[structopt(long, default_value = "3")]
+        context: usize,
+        /// Truncate each field's diff after this many lines
+        #[structopt(long)]
+        max_diff_lines: Option<usize>,
+        /// `auto`, `always` or `never`
+        #[structopt(long, default_value = "auto", possible_values = &["auto", "always", "never"])]
+        color: String,
+        /// Flag files where a stage got more than this many percent slower
+        #[structopt(long)]
+        timing_threshold: Option<f64>,
+        /// Ignore slowdowns smaller than this many microseconds
+        #[structopt(long, default_value = "1000")]
+        timing_min_us: i64,
+        #[structopt(flatten)]
+        filter: CorpusFilter,
+    },
+    /// Summarize how a run did: failure rates overall and per repo, and the
+    /// most common parse error kinds
+    #[structopt(name = "stats")]
+    Stats {
+        #[structopt(short, long)]
+        results_db: String,
+        /// Run to summarize, by name or id (defaults to the latest run)
+        #[structopt(long)]
+        run: Option<String>,
+        /// `text` or `json`
+        #[structopt(long, default_value = "text")]
+        format: OutputFormat,
+        /// How many error kinds to list
+        #[structopt(long, default_value = "10")]
+        top: usize,
+    },
+    /// Write failing files out as test_syntax snapshots, in `pass`, `fail`
+    /// and `malformed` directories like roc's own
+    #[structopt(name = "export-snapshots")]
+    ExportSnapshots {
+        #[structopt(short, long)]
+        results_db: String,
+        /// Run to export from, by name or id (defaults to the latest run)
+        #[structopt(long)]
+        run: Option<String>,
+        /// Snapshot directory to write into, e.g. roc's
+        /// crates/compiler/test_syntax/tests/snapshots
+        #[structopt(short, long)]
+        out: String,
+        /// Which failures to export (repeatable); defaults to everything but
+        /// plain parse errors, unless --id picks the rows
+        #[structopt(long, possible_values = FAILURE_KINDS)]
+        failure: Vec<String>,
+        /// Only these results row ids, whatever they failed (repeatable)
+        #[structopt(long)]
+        id: Vec<i64>,
+        /// Export at most this many files
+        #[structopt(long)]
+        limit: Option<usize>,
+    },
+    /// Shrink a failing file to a minimal input with the same failure
+    #[structopt(name = "minimize")]
+    Minimize {
+        #[structopt(short, long)]
+        results_db: Option<String>,
+        /// Run to take the file from, by name or id (defaults to the latest run)
+        #[structopt(long)]
+        run: Option<String>,
+        /// Results row id
+        #[structopt(long)]
+        id: Option<i64>,
+        #[structopt(long)]
+        repo: Option<String>,
+        #[structopt(long)]
+        path: Option<String>,
+        /// Minimize a .roc file instead of a results row
+        #[structopt(long)]
+        input: Option<String>,
+        /// Where to write the reproducer (defaults to stdout)
+        #[structopt(short, long)]
+        out: Option<String>,
+        /// Run each candidate in a worker process with this timeout, for
+        /// inputs that hang or crash the parser
+        #[structopt(long)]
+        timeout_ms: Option<u64>,
+        /// Stack size for the worker's parser thread; pass the one the row
+        /// was parsed with to reproduce a stack overflow
+        #[structopt(long)]
+        stack_size_mb: Option<usize>,
+        /// Heap ceiling for the worker, needed to reproduce an `oom` outcome
+        #[structopt(long)]
+        memory_limit_mb: Option<usize>,
+    },
+    /// Print everything recorded for one file: source, errors, fmt output,
+    /// and diffs between the stages
+    #[structopt(name = "show")]
+    Show {
+        #[structopt(short, long)]
+        results_db: Option<String>,
+        /// Run to take the file from, by name or id (defaults to the latest run)
+        #[structopt(long)]
+        run: Option<String>,
+        /// Results row id
+        #[structopt(long)]
+        id: Option<i64>,
+        #[structopt(long)]
+        repo: Option<String>,
+        #[structopt(long)]
+        path: Option<String>,
+        /// Run a .roc file through the pipeline now instead of reading a
+        /// results row
+        #[structopt(long)]
+        input: Option<String>,
+        /// `auto`, `always` or `never`
+        #[structopt(long, default_value = "auto", possible_values = &["auto", "always", "never"])]
+        color: String,
+    },
+    /// Write the corpus out as seeds for the fuzz targets in `fuzz/`, one
+    /// file per distinct input
+    #[structopt(name = "export-fuzz-corpus")]
+    ExportFuzzCorpus {
+        #[structopt(flatten)]
+        source: CorpusSource,
+        /// zulip_code_blocks.db from scrape_zulip.py, whose code blocks are
+        /// added as seeds too
+        #[structopt(long)]
+        zulip_db: Option<String>,
+        /// Corpus directory to write into, e.g. fuzz/corpus/fmt_module
+        #[structopt(short, long)]
+        out: String,
+        /// Skip inputs longer than this many bytes
+        #[structopt(long, default_value = "65536")]
+        max_len: usize,
+        /// Also export inputs that already fail, which fmt_module would
+        /// crash on straight away; parse_module only crashes on panics
+        #[structopt(long)]
+        keep_failing: bool,
+        /// Count an input as failing if checking it takes longer than this
+        #[structopt(long, default_value = "10000")]
+        timeout_ms: u64,
+        #[structopt(flatten)]
+        filter: CorpusFilter,
+    },
+    /// Generate random well-formed programs and check each one the way
+    /// `parse` checks corpus files
+    #[structopt(name = "generate")]
+    Generate {
+        /// Seed of the first program; the rest use the seeds after it
+        #[structopt(long, default_value = "0")]
+        seed: u64,
+        /// Number of programs to generate
+        #[structopt(short = "n", long, default_value = "100")]
+        count: u64,
+        /// Roughly how many AST nodes each program gets
+        #[structopt(long, default_value = "40")]
+        budget: usize,
+        /// Print the programs, as roc_fmt writes them, instead of checking
+        /// them
+        #[structopt(long)]
+        print: bool,
+        /// Also store every program's results as a run, for `stats`, `show`
+        /// and `export-snapshots`
+        #[structopt(short, long)]
+        results_db: Option<String>,
+        /// Name for the stored run
+        #[structopt(long)]
+        run_name: Option<String>,
+    },
+    #[structopt(name = "worker", setting = structopt::clap::AppSettings::Hidden)]
+    Worker {
+        #[structopt(long)]
+        stack_size: Option<usize>,
+        #[structopt(long)]
+        memory_limit: Option<usize>,
+        #[structopt(long)]
+        max_fmt_passes: Option<usize>,
     },
 }
 
 fn main() -> Result<()> {
     let opt = Opt::from_args();
+    install_panic_hook();
 
     match opt {
         Opt::Parse {
-            corpus_db,
+            source,
             results_db,
+            jobs,
+            batch_size,
+            isolate,
+            timeout_ms,
+            stack_size_mb,
+            memory_limit_mb,
+            incremental,
+            run_name,
+            max_fmt_passes,
+            filter,
         } => {
-            let conn_corpus = Connection::open(corpus_db)?;
+            MAX_FMT_PASSES.store(max_fmt_passes, Ordering::Relaxed);
+            let conn_corpus = source.open()?;
             let mut conn_results = Connection::open(results_db)?;
 
-            // Ensure the output table exists
-            conn_results.execute(
-                "CREATE TABLE IF NOT EXISTS roc_parse_results (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    repo_url TEXT,
-                    file_path TEXT,
-                    contents TEXT,
-                    output TEXT,
-                    error TEXT,
-                    fmt_output TEXT,
-                    reparse_output TEXT,
-                    reparse_error TEXT,
-                    normalized_output TEXT,
-                    normalized_reparse_output TEXT,
-                    double_fmt_output TEXT,
-                    fmt_changed TEXT,
-                    fmt_changed_syntax BOOL,
-                    fmt_idempotent BOOL
-                )",
-                [],
+            ensure_results_schema(&conn_results)?;
+
+            let jobs = jobs.unwrap_or_else(|| {
+                thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            });
+
+            let limits = WorkerLimits {
+                timeout: timeout_ms.map(Duration::from_millis),
+                stack_size: stack_size_mb.map(|mb| mb * 1024 * 1024),
+                memory_limit: memory_limit_mb.map(|mb| mb * 1024 * 1024),
+                max_fmt_passes: Some(max_fmt_passes),
+            };
+            let isolate = isolate
+                || limits.timeout.is_some()
+                || limits.memory_limit.is_some()
+                || source.is_fuzz_artifacts();
+
+            let options = ParseOptions {
+                jobs: jobs.max(1),
+                batch_size: batch_size.max(1),
+                isolate,
+                limits,
+                incremental,
+            };
+
+            let entries = filter.select(&conn_corpus)?;
+            let corpus_retrieved_at = entries
+                .iter()
+                .filter_map(|entry| entry.retrieval_date.clone())
+                .max();
+            let run_id = create_run(
+                &conn_results,
+                &RunInfo {
+                    name: run_name,
+                    command_line: std::env::args().collect::<Vec<_>>().join(" "),
+                    corpus_db: source.describe(),
+                    corpus_files: entries.len() as i64,
+                    corpus_retrieved_at,
+                },
             )?;
 
-            let mut stmt = conn_corpus.prepare(
-                "SELECT repo_url, file_path, file_contents FROM roc_files where repo_url not like '%/roc'",
-            )?;
-            let file_contents_iter = stmt.query_map([], |row| {
-                let repo_url = row.get::<_, String>(0)?;
-                let file_path = row.get::<_, String>(1)?;
-                let file_contents = row.get::<_, String>(2)?;
-
-                Ok((repo_url, file_path, file_contents))
-            })?;
-
-            let transaction = conn_results.transaction()?;
-
-            for row in file_contents_iter {
-                let (repo_url, file_path, file_content) = row?;
-                println!("Parsing file: {} {}", repo_url, file_path);
-                let result: ParseData = parse_one(&file_content);
-
-                transaction.execute(
-                    "INSERT INTO roc_parse_results (
-                        repo_url, file_path,
-                        contents, output, error, fmt_output, reparse_output, reparse_error,
-                        normalized_output, normalized_reparse_output, double_fmt_output,
-                        fmt_changed, fmt_changed_syntax, fmt_idempotent
-                    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
-                    params![
-                        repo_url,
-                        file_path,
-                        file_content,
-                        result.output,
-                        result.error,
-                        result.fmt_output,
-                        result.reparse_output,
-                        result.reparse_error,
-                        result.normalized_output,
-                        result.normalized_reparse_output,
-                        result.double_fmt_output,
-                        result.fmt_changed,
-                        result.fmt_changed_syntax,
-                        result.fmt_idempotent
-                    ],
-                )?;
+            if let Err(e) =
+                parse_corpus(&conn_corpus, &entries, &mut conn_results, run_id, &options)
+            {
+                eprintln!("Run {} did not finish: {}", run_id, e);
+                std::process::exit(1);
             }
 
-            transaction.commit()?;
+            finish_run(&conn_results, run_id)?;
+            println!("Finished run {}", run_id);
         }
         Opt::Diff {
-            corpus_db,
+            source,
+            results_db,
             results_db_a,
             results_db_b,
+            run_a,
+            run_b,
+            format,
+            show_diff,
+            context,
+            max_diff_lines,
+            color,
+            timing_threshold,
+            timing_min_us,
+            filter,
         } => {
-            let conn_corpus = Connection::open(corpus_db)?;
-            let conn_results_a = Connection::open(results_db_a)?;
-            let conn_results_b = Connection::open(results_db_b)?;
+            let (Some(results_db_a), Some(results_db_b)) = (
+                results_db_a.or_else(|| results_db.clone()),
+                results_db_b.or(results_db),
+            ) else {
+                eprintln!("diff needs --results-db, or both --results-db-a and --results-db-b");
+                std::process::exit(2);
+            };
+
+            let conn_corpus = source.open()?;
+            let conn_results_a = Connection::open(&results_db_a)?;
+            let conn_results_b = Connection::open(&results_db_b)?;
+            ensure_results_schema(&conn_results_a)?;
+            ensure_results_schema(&conn_results_b)?;
+
+            let run_b = find_run(&conn_results_b, &results_db_b, run_b.as_deref())?;
+            let same_db = results_db_a == results_db_b;
+            let run_a = match run_a {
+                None if same_db => match previous_run(&conn_results_a, run_b)? {
+                    Some(run_a) => run_a,
+                    None => {
+                        eprintln!(
+                            "No run before run {} in {} to diff it against",
+                            run_b, results_db_a
+                        );
+                        std::process::exit(1);
+                    }
+                },
+                run_a => find_run(&conn_results_a, &results_db_a, run_a.as_deref())?,
+            };
+            if same_db && run_a == run_b {
+                eprintln!(
+                    "Both sides are run {} in {}; nothing to diff",
+                    run_a, results_db_a
+                );
+                std::process::exit(2);
+            }
 
-            let mut stmt = conn_corpus.prepare(
-                "SELECT repo_url, file_path FROM roc_files where repo_url not like '%/roc'",
+            let color = match color.as_str() {
+                "always" => true,
+                "never" => false,
+                _ => std::io::stdout().is_terminal(),
+            };
+            let options = DiffOptions {
+                format,
+                show_diff,
+                context,
+                max_diff_lines,
+                color,
+                timing_threshold,
+                timing_min_us,
+            };
+
+            let summary = diff_runs(
+                &filter.select(&conn_corpus)?,
+                &conn_results_a,
+                run_a,
+                &conn_results_b,
+                run_b,
+                &options,
             )?;
-            let file_paths_iter = stmt.query_map([], |row| {
-                let repo_url = row.get::<_, String>(0)?;
-                let file_path = row.get::<_, String>(1)?;
-
-                Ok((repo_url, file_path))
-            })?;
-
-            for row in file_paths_iter {
-                let (repo_url, file_path) = row?;
-
-                let result_a: Option<ParseData> = conn_results_a
-                    .query_row(
-                        "SELECT contents, output, error, fmt_output, reparse_output, reparse_error,
-                        normalized_output, normalized_reparse_output, double_fmt_output,
-                        fmt_changed, fmt_changed_syntax, fmt_idempotent
-                     FROM roc_parse_results
-                     WHERE repo_url = ?1 and file_path = ?2",
-                        params![&repo_url, &file_path],
-                        |row| {
-                            Ok(ParseData {
-                                output: row.get(1)?,
-                                error: row.get(2)?,
-                                fmt_output: row.get(3)?,
-                                reparse_output: row.get(4)?,
-                                reparse_error: row.get(5)?,
-                                normalized_output: row.get(6)?,
-                                normalized_reparse_output: row.get(7)?,
-                                double_fmt_output: row.get(8)?,
-                                fmt_changed: row.get(9)?,
-                                fmt_changed_syntax: row.get(10)?,
-                                fmt_idempotent: row.get(11)?,
-                            })
-                        },
-                    )
-                    .optional()?;
-
-                let result_b: Option<ParseData> = conn_results_b
-                    .query_row(
-                        "SELECT contents, output, error, fmt_output, reparse_output, reparse_error,
-                        normalized_output, normalized_reparse_output, double_fmt_output,
-                        fmt_changed, fmt_changed_syntax, fmt_idempotent
-                     FROM roc_parse_results
-                     WHERE repo_url = ?1 and file_path = ?2",
-                        params![&repo_url, &file_path],
-                        |row| {
-                            Ok(ParseData {
-                                output: row.get(1)?,
-                                error: row.get(2)?,
-                                fmt_output: row.get(3)?,
-                                reparse_output: row.get(4)?,
-                                reparse_error: row.get(5)?,
-                                normalized_output: row.get(6)?,
-                                normalized_reparse_output: row.get(7)?,
-                                double_fmt_output: row.get(8)?,
-                                fmt_changed: row.get(9)?,
-                                fmt_changed_syntax: row.get(10)?,
-                                fmt_idempotent: row.get(11)?,
-                            })
-                        },
-                    )
-                    .optional()?;
-
-                match (result_a, result_b) {
-                    (Some(a), Some(b)) => {
-                        let mut differences = Vec::new();
 
-                        if a.output != b.output {
-                            differences.push("output");
-                        }
-                        if a.error != b.error {
-                            differences.push("error");
-                        }
-                        if a.fmt_output != b.fmt_output {
-                            differences.push("fmt_output");
-                        }
-                        if a.reparse_output != b.reparse_output {
-                            differences.push("reparse_output");
-                        }
-                        if a.reparse_error != b.reparse_error {
-                            differences.push("reparse_error");
-                        }
-                        if a.normalized_output != b.normalized_output {
-                            differences.push("normalized_output");
-                        }
-                        if a.normalized_reparse_output != b.normalized_reparse_output {
-                            differences.push("normalized_reparse_output");
+            // Lets a roc bump be gated on `osprey_parse diff` in CI.
+            if summary.regressions > 0 {
+                std::process::exit(1);
+            }
+        }
+        Opt::Stats {
+            results_db,
+            run,
+            format,
+            top,
+        } => {
+            let conn = Connection::open(&results_db)?;
+            ensure_results_schema(&conn)?;
+            let run_id = find_run(&conn, &results_db, run.as_deref())?;
+            report_stats(&compute_stats(&conn, run_id, top)?, format);
+        }
+        Opt::ExportSnapshots {
+            results_db,
+            run,
+            out,
+            failure,
+            id,
+            limit,
+        } => {
+            let conn = Connection::open(&results_db)?;
+            ensure_results_schema(&conn)?;
+            let run_id = find_run(&conn, &results_db, run.as_deref())?;
+            export_snapshots(
+                &conn,
+                run_id,
+                &ExportOptions {
+                    out: Path::new(&out),
+                    failures: &failure,
+                    ids: &id,
+                    limit,
+                },
+            )?;
+        }
+        Opt::Minimize {
+            results_db,
+            run,
+            id,
+            repo,
+            path,
+            input,
+            out,
+            timeout_ms,
+            stack_size_mb,
+            memory_limit_mb,
+        } => {
+            let (source, recorded) = match (input, results_db) {
+                (Some(input), _) => {
+                    let source = std::fs::read_to_string(&input).unwrap_or_else(|e| {
+                        eprintln!("Failed to read {}: {}", input, e);
+                        std::process::exit(1);
+                    });
+                    (source, None)
+                }
+                (None, Some(results_db)) => {
+                    let conn = Connection::open(&results_db)?;
+                    ensure_results_schema(&conn)?;
+                    let found = match (id, repo, path) {
+                        (Some(id), _, _) => {
+                            load_contents_by_id(&conn, id)?.zip(load_result_by_id(&conn, id)?)
                         }
-                        if a.double_fmt_output != b.double_fmt_output {
-                            differences.push("double_fmt_output");
+                        (None, Some(repo), Some(path)) => {
+                            let run_id = find_run(&conn, &results_db, run.as_deref())?;
+                            load_contents(&conn, run_id, &repo, &path)?
+                                .zip(load_result(&conn, run_id, &repo, &path)?)
                         }
-                        if a.fmt_changed != b.fmt_changed {
-                            differences.push("fmt_changed");
+                        _ => {
+                            eprintln!("minimize needs --id, or both --repo and --path");
+                            std::process::exit(2);
                         }
-                        if a.fmt_changed_syntax != b.fmt_changed_syntax {
-                            differences.push("fmt_changed_syntax");
+                    };
+                    let Some((source, recorded)) = found else {
+                        eprintln!("No such results row in {}", results_db);
+                        std::process::exit(1);
+                    };
+                    (source, Some(recorded))
+                }
+                (None, None) => {
+                    eprintln!("minimize needs --input or --results-db");
+                    std::process::exit(2);
+                }
+            };
+
+            run_minimize(
+                &source,
+                recorded.as_ref(),
+                out.as_deref(),
+                WorkerLimits {
+                    timeout: timeout_ms.map(Duration::from_millis),
+                    stack_size: stack_size_mb.map(|mb| mb * 1024 * 1024),
+                    memory_limit: memory_limit_mb.map(|mb| mb * 1024 * 1024),
+                    ..WorkerLimits::default()
+                },
+            );
+        }
+        Opt::Show {
+            results_db,
+            run,
+            id,
+            repo,
+            path,
+            input,
+            color,
+        } => {
+            let color = match color.as_str() {
+                "always" => true,
+                "never" => false,
+                _ => std::io::stdout().is_terminal(),
+            };
+            match (input, results_db) {
+                (Some(input), _) => {
+                    let source = std::fs::read_to_string(&input).unwrap_or_else(|e| {
+                        eprintln!("Failed to read {}: {}", input, e);
+                        std::process::exit(1);
+                    });
+                    show::show(&input, &source, &parse_one(&source), color);
+                }
+                (None, Some(results_db)) => {
+                    let conn = Connection::open(&results_db)?;
+                    ensure_results_schema(&conn)?;
+                    let found = match (id, repo, path) {
+                        (Some(id), _, _) => match load_row_key(&conn, id)? {
+                            Some((_, repo, path)) => load_contents_by_id(&conn, id)?
+                                .zip(load_result_by_id(&conn, id)?)
+                                .map(|found| (repo, path, found)),
+                            None => None,
+                        },
+                        (None, Some(repo), Some(path)) => {
+                            let run_id = find_run(&conn, &results_db, run.as_deref())?;
+                            load_contents(&conn, run_id, &repo, &path)?
+                                .zip(load_result(&conn, run_id, &repo, &path)?)
+                                .map(|found| (repo, path, found))
                         }
-                        if a.fmt_idempotent != b.fmt_idempotent {
-                            differences.push("fmt_idempotent");
+                        _ => {
+                            eprintln!("show needs --id, or both --repo and --path");
+                            std::process::exit(2);
                         }
+                    };
+                    let Some((repo, path, (source, data))) = found else {
+                        eprintln!("No such results row in {}", results_db);
+                        std::process::exit(1);
+                    };
+                    show::show(&format!("{} {}", repo, path), &source, &data, color);
+                }
+                (None, None) => {
+                    eprintln!("show needs --input or --results-db");
+                    std::process::exit(2);
+                }
+            }
+        }
+        Opt::ExportFuzzCorpus {
+            source,
+            zulip_db,
+            out,
+            max_len,
+            keep_failing,
+            timeout_ms,
+            filter,
+        } => {
+            let conn_corpus = source.open()?;
+            fuzz_corpus::export_seeds(
+                &conn_corpus,
+                &filter.select(&conn_corpus)?,
+                &fuzz_corpus::SeedOptions {
+                    out: Path::new(&out),
+                    zulip_db: zulip_db.as_deref(),
+                    max_len,
+                    keep_failing,
+                    limits: WorkerLimits {
+                        timeout: Some(Duration::from_millis(timeout_ms)),
+                        ..WorkerLimits::default()
+                    },
+                },
+            )?;
+        }
+        Opt::Generate {
+            seed,
+            count,
+            budget,
+            print,
+            results_db,
+            run_name,
+        } => {
+            if print {
+                for seed in seed..seed.saturating_add(count) {
+                    print!("{}", generate::source(seed, budget));
+                }
+                return Ok(());
+            }
 
-                        if !differences.is_empty() {
-                            println!("{} {}: {}", repo_url, file_path, differences.join(", "));
-                        }
-                    }
-                    _ => println!("No results found for file: {} {}", repo_url, file_path),
+            let mut conn_results = match &results_db {
+                Some(results_db) => {
+                    let conn = Connection::open(results_db)?;
+                    ensure_results_schema(&conn)?;
+                    Some(conn)
                 }
+                None => None,
+            };
+            let run_id = match &conn_results {
+                Some(conn) => Some(create_run(
+                    conn,
+                    &RunInfo {
+                        name: run_name,
+                        command_line: std::env::args().collect::<Vec<_>>().join(" "),
+                        corpus_db: format!("generate:budget={}", budget),
+                        corpus_files: count as i64,
+                        corpus_retrieved_at: None,
+                    },
+                )?),
+                None => None,
+            };
+
+            let options = GenerateOptions {
+                seed,
+                count,
+                budget,
+            };
+            let failed = run_generate(&options, conn_results.as_mut().zip(run_id))?;
+
+            if let (Some(conn), Some(run_id)) = (&conn_results, run_id) {
+                finish_run(conn, run_id)?;
+                println!("Finished run {}", run_id);
+            }
+            println!("{} of {} generated programs had problems", failed, count);
+            if failed > 0 {
+                println!(
+                    "Replay one with: generate --seed N --count 1 --budget {} --print",
+                    budget
+                );
+                std::process::exit(1);
+            }
+        }
+        Opt::Worker {
+            stack_size,
+            memory_limit,
+            max_fmt_passes,
+        } => {
+            if let Some(max_fmt_passes) = max_fmt_passes {
+                MAX_FMT_PASSES.store(max_fmt_passes, Ordering::Relaxed);
+            }
+            if let Err(e) = worker::run_worker(stack_size, memory_limit) {
+                eprintln!("worker failed: {}", e);
+                std::process::exit(1);
             }
         }
     }
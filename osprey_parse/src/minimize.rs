@@ -0,0 +1,195 @@
+use std::ops::Range;
+
+use crate::ParseData;
+
+// The verdicts `minimize` knows how to preserve. Plain parse errors are left
+// out on purpose: almost any deletion keeps a broken file broken, so the
+// "minimal" reproducer would be meaningless.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Failure {
+    Panic(String),
+    ReparseError,
+    ChangedSyntax,
+    NotIdempotent,
+    Outcome(String),
+}
+
+impl Failure {
+    pub fn describe(&self) -> String {
+        match self {
+            Failure::Panic(stage) => format!("panic in {}", stage),
+            Failure::ReparseError => "formatted output fails to parse".to_string(),
+            Failure::ChangedSyntax => "formatting changes the syntax tree".to_string(),
+            Failure::NotIdempotent => "formatting is not idempotent".to_string(),
+            Failure::Outcome(outcome) => format!("worker outcome {}", outcome),
+        }
+    }
+}
+
+// Most severe first, which is also the order `parse_one` would hit them in.
+pub fn failures(data: &ParseData) -> Vec<Failure> {
+    let mut failures = Vec::new();
+    if let Some(outcome) = &data.outcome {
+        if outcome != crate::worker::OUTCOME_COMPLETED {
+            failures.push(Failure::Outcome(outcome.clone()));
+        }
+    }
+    if let Some(stage) = &data.panic_stage {
+        failures.push(Failure::Panic(stage.clone()));
+    }
+    if data.reparse_error.is_some() {
+        failures.push(Failure::ReparseError);
+    }
+    if data.fmt_changed_syntax == Some(true) {
+        failures.push(Failure::ChangedSyntax);
+    }
+    if data.fmt_idempotent == Some(false) {
+        failures.push(Failure::NotIdempotent);
+    }
+    failures
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Granularity {
+    Def,
+    Line,
+    Token,
+}
+
+pub struct Minimized {
+    pub source: String,
+    pub attempts: usize,
+}
+
+// Greedy delta debugging: at each granularity, try deleting chunks of units,
+// halving the chunk size whenever no chunk can go, and start over from the
+// coarsest granularity whenever a pass made progress.
+pub fn minimize(
+    source: &str,
+    target: &Failure,
+    mut run: impl FnMut(&str) -> ParseData,
+) -> Minimized {
+    let mut current = source.to_string();
+    let mut attempts = 0;
+
+    let mut reproduces = |candidate: &str| {
+        attempts += 1;
+        failures(&run(candidate)).contains(target)
+    };
+
+    loop {
+        let before = current.len();
+
+        for granularity in [Granularity::Def, Granularity::Line, Granularity::Token] {
+            let mut chunk = units(&current, granularity).len() / 2;
+            while chunk >= 1 {
+                let mut i = 0;
+                loop {
+                    let units = units(&current, granularity);
+                    if i >= units.len() {
+                        break;
+                    }
+                    let end = (i + chunk).min(units.len());
+                    let candidate = remove(&current, units[i].start..units[end - 1].end);
+
+                    if reproduces(&candidate) {
+                        current = candidate;
+                    } else {
+                        i += chunk;
+                    }
+                }
+                chunk /= 2;
+            }
+        }
+
+        if current.len() == before {
+            break;
+        }
+    }
+
+    Minimized {
+        source: current,
+        attempts,
+    }
+}
+
+fn remove(source: &str, range: Range<usize>) -> String {
+    let mut out = String::with_capacity(source.len() - range.len());
+    out.push_str(&source[..range.start]);
+    out.push_str(&source[range.end..]);
+    out
+}
+
+fn units(source: &str, granularity: Granularity) -> Vec<Range<usize>> {
+    match granularity {
+        Granularity::Def => def_units(source),
+        Granularity::Line => line_units(source),
+        Granularity::Token => token_units(source),
+    }
+}
+
+fn line_units(source: &str) -> Vec<Range<usize>> {
+    let mut units = Vec::new();
+    let mut start = 0;
+    for (i, _) in source.match_indices('\n') {
+        units.push(start..i + 1);
+        start = i + 1;
+    }
+    if start < source.len() {
+        units.push(start..source.len());
+    }
+    units
+}
+
+// Top-level defs (and the header) start in column 0 and everything indented
+// below them belongs to them, so blocks of lines split at unindented lines
+// are a good enough stand-in for the defs in the AST, and still work on
+// candidates that no longer parse.
+fn def_units(source: &str) -> Vec<Range<usize>> {
+    let mut units: Vec<Range<usize>> = Vec::new();
+    for line in line_units(source) {
+        let text = &source[line.clone()];
+        let starts_def = !text.starts_with([' ', '\t', '\n', '\r']);
+        match units.last_mut() {
+            Some(last) if !starts_def => last.end = line.end,
+            _ => units.push(line),
+        }
+    }
+    units
+}
+
+fn token_units(source: &str) -> Vec<Range<usize>> {
+    let bytes = source.as_bytes();
+    let mut units = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        } else if c.is_ascii_alphanumeric() || c == b'_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+        } else if c == b'"' {
+            i += 1;
+            while i < bytes.len() && bytes[i] != b'"' && bytes[i] != b'\n' {
+                i += if bytes[i] == b'\\' { 2 } else { 1 };
+            }
+            i = (i + 1).min(bytes.len());
+        } else if c == b'#' {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+        } else {
+            i += 1;
+        }
+        // Keep multi-byte UTF-8 characters in one piece
+        while !source.is_char_boundary(i) {
+            i += 1;
+        }
+        units.push(start..i);
+    }
+    units
+}
@@ -0,0 +1,127 @@
+// roc_parse's error enums nest one variant per parser layer and carry their
+// position in whichever field that layer happens to use, with no common
+// accessor. Their Debug output is regular enough to recover both, e.g.
+//
+//     (MadeProgress, Expr(When(Arrow(@12), @5), @0))
+//
+// is kind `Expr/When/Arrow` at byte 12: the innermost position is the first
+// one printed.
+pub struct ParseError {
+    pub debug: String,
+    pub kind: String,
+    pub offset: Option<usize>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub source_line: Option<String>,
+}
+
+impl ParseError {
+    pub fn new(debug: String, source: &str) -> ParseError {
+        let kind = variant_path(&debug);
+        let offset = first_offset(&debug).map(|offset| offset.min(source.len()));
+
+        let mut error = ParseError {
+            debug,
+            kind,
+            offset,
+            line: None,
+            column: None,
+            source_line: None,
+        };
+
+        if let Some(offset) = offset {
+            // Offsets from a parser that stopped inside a multi-byte
+            // character still belong to that character's line.
+            let offset = (0..=offset)
+                .rev()
+                .find(|&i| source.is_char_boundary(i))
+                .unwrap_or(0);
+            let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+            let line_end = source[offset..]
+                .find('\n')
+                .map_or(source.len(), |i| offset + i);
+
+            // 1-based, with columns counted in characters, as editors show them
+            error.line = Some(source[..line_start].matches('\n').count() + 1);
+            error.column = Some(source[line_start..offset].chars().count() + 1);
+            error.source_line = Some(
+                source[line_start..line_end]
+                    .trim_end_matches('\r')
+                    .to_string(),
+            );
+        }
+
+        error
+    }
+}
+
+fn variant_path(debug: &str) -> String {
+    let mut rest = debug.trim_start_matches('(');
+    for progress in ["MadeProgress, ", "NoProgress, "] {
+        rest = rest.strip_prefix(progress).unwrap_or(rest);
+    }
+
+    let mut path = Vec::new();
+    loop {
+        let end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        // Structs such as a Position with a derived Debug are payload, not
+        // part of the path
+        if end == 0 || rest[end..].starts_with(" {") {
+            break;
+        }
+        path.push(&rest[..end]);
+        match rest[end..].strip_prefix('(') {
+            Some(inner) => rest = inner,
+            None => break,
+        }
+    }
+    path.join("/")
+}
+
+// Position prints as `@12` and Region as `@12-15`; a derived Debug would give
+// `offset: 12` instead, so accept either.
+fn first_offset(debug: &str) -> Option<usize> {
+    let mut starts: Vec<usize> = debug
+        .match_indices('@')
+        .map(|(i, _)| i + 1)
+        .chain(debug.match_indices("offset: ").map(|(i, m)| i + m.len()))
+        .collect();
+    starts.sort_unstable();
+
+    starts.into_iter().find_map(|start| {
+        let digits = &debug[start..];
+        let end = digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(digits.len());
+        digits[..end].parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_error_takes_innermost_variant_and_position() {
+        let debug = "(MadeProgress, Expr(When(Arrow(@12), @5), @0))";
+        assert_eq!(variant_path(debug), "Expr/When/Arrow");
+        assert_eq!(first_offset(debug), Some(12));
+
+        let debug = "Header(Exposes(ListEnd(Position { offset: 31 }), @20), @0)";
+        assert_eq!(variant_path(debug), "Header/Exposes/ListEnd");
+        assert_eq!(first_offset(debug), Some(31));
+    }
+
+    #[test]
+    fn offset_inside_multi_byte_character() {
+        // "é" is bytes 15..17, so @16 is its second byte
+        let source = "module []\n\nx = é\n";
+        let error = ParseError::new("Expr(Start(@16), @0)".to_string(), source);
+        assert_eq!(error.offset, Some(16));
+        assert_eq!(error.line, Some(3));
+        assert_eq!(error.column, Some(5));
+        assert_eq!(error.source_line.as_deref(), Some("x = é"));
+    }
+}
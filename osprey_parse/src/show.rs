@@ -0,0 +1,252 @@
+use annotate_snippets::{Level, Renderer, Snippet};
+
+use crate::diff::{colorize, unified_diff, DiffOptions, OutputFormat};
+use crate::ParseData;
+
+// Everything recorded for one file, stage by stage, so a failure can be read
+// without pulling blobs out of `roc_parse_results` by hand.
+pub fn show(origin: &str, source: &str, data: &ParseData, color: bool) {
+    let options = DiffOptions {
+        format: OutputFormat::Text,
+        show_diff: true,
+        context: 3,
+        max_diff_lines: None,
+        color,
+        timing_threshold: None,
+        timing_min_us: 0,
+    };
+    let section = |title: &str| {
+        println!();
+        println!("{}", colorize_title(&format!("== {} ==", title), color));
+    };
+
+    section(&format!("source: {}", origin));
+    print!("{}", source);
+    if !source.ends_with('\n') {
+        println!();
+    }
+
+    if let Some(outcome) = data.outcome.as_deref() {
+        if outcome != crate::worker::OUTCOME_COMPLETED {
+            section("outcome");
+            println!("{}", outcome);
+            if let Some(details) = &data.outcome_details {
+                println!("{}", details);
+            }
+        }
+    }
+    if let (Some(stage), Some(message)) = (&data.panic_stage, &data.panic_message) {
+        section(&format!("panic in {}", stage));
+        println!("{}", message);
+    }
+
+    if let Some(error) = &data.error {
+        section(&format!(
+            "parse error ({})",
+            data.entrypoint.as_deref().unwrap_or("module")
+        ));
+        println!(
+            "{}",
+            annotated(
+                origin,
+                source,
+                data.error_offset,
+                data.error_kind.as_deref(),
+                color
+            )
+        );
+        println!("{}", error);
+    }
+
+    show_defs(data);
+
+    let Some(formatted) = &data.fmt_output else {
+        return;
+    };
+    section("fmt output");
+    print!("{}", formatted);
+    if !formatted.ends_with('\n') {
+        println!();
+    }
+    section("source -> fmt");
+    print_diff(
+        &unified_diff(Some(source), Some(formatted), &options),
+        color,
+    );
+
+    if let Some(error) = &data.reparse_error {
+        section("reparse error");
+        println!(
+            "{}",
+            annotated(
+                "fmt output",
+                formatted,
+                data.reparse_error_offset,
+                data.reparse_error_kind.as_deref(),
+                color
+            )
+        );
+        println!("{}", error);
+    }
+
+    if let Some(double_formatted) = &data.double_fmt_output {
+        section("fmt -> double fmt");
+        print_diff(
+            &unified_diff(Some(formatted), Some(double_formatted), &options),
+            color,
+        );
+    }
+
+    if data.normalized_output.is_some() {
+        section("normalized AST diff");
+        print_diff(
+            &unified_diff(
+                data.normalized_output.as_deref(),
+                data.normalized_reparse_output.as_deref(),
+                &options,
+            ),
+            color,
+        );
+        if let Some(path) = &data.divergence_path {
+            println!(
+                "first divergence at {} ({})",
+                path,
+                data.divergence_kind.as_deref().unwrap_or("")
+            );
+        }
+    }
+
+    let checks = [
+        ("comments lost", &data.comments_lost),
+        ("comments duplicated", &data.comments_duplicated),
+        ("comments moved", &data.comments_moved),
+        ("hygiene violations", &data.fmt_hygiene_violations),
+    ];
+    if checks.iter().any(|(_, value)| value.is_some()) || data.fmt_idempotent == Some(false) {
+        section("checks");
+        for (label, value) in checks {
+            if let Some(value) = value {
+                println!("{}: {}", label, value);
+            }
+        }
+        if let Some(convergence) = &data.fmt_convergence {
+            println!(
+                "fmt convergence: {}{}",
+                convergence,
+                data.fmt_pass_sizes
+                    .as_ref()
+                    .map(|sizes| format!(", pass sizes {}", sizes))
+                    .unwrap_or_default()
+            );
+        }
+    }
+}
+
+// Only the defs that failed a check, since that's what narrows a bug down.
+fn show_defs(data: &ParseData) {
+    let failing: Vec<_> = data
+        .def_results
+        .iter()
+        .filter_map(|def| {
+            let mut failures = Vec::new();
+            if let Some(kind) = &def.error_kind {
+                failures.push(format!("parse error {}", kind));
+            }
+            if let Some(kind) = &def.reparse_error_kind {
+                failures.push(format!("reparse error {}", kind));
+            }
+            if def.fmt_changed_syntax == Some(true) {
+                failures.push("fmt changes syntax".to_string());
+            }
+            if def.fmt_idempotent == Some(false) {
+                failures.push("fmt not idempotent".to_string());
+            }
+            if let Some(message) = &def.panic_message {
+                failures.push(format!("panic: {}", message));
+            }
+            (!failures.is_empty()).then_some((def, failures))
+        })
+        .collect();
+    if failing.is_empty() {
+        return;
+    }
+
+    println!();
+    println!(
+        "== {} of {} top-level defs failing ==",
+        failing.len(),
+        data.def_results.len()
+    );
+    for (def, failures) in failing {
+        println!(
+            "#{} {} @{}-{}: {}",
+            def.index,
+            def.name,
+            def.start,
+            def.end,
+            failures.join(", ")
+        );
+    }
+}
+
+fn print_diff(diff: &str, color: bool) {
+    if diff.is_empty() {
+        println!("(no changes)");
+    }
+    for line in diff.lines() {
+        println!("{}", colorize(line, color));
+    }
+}
+
+fn colorize_title(title: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[1m{}\x1b[0m", title)
+    } else {
+        title.to_string()
+    }
+}
+
+// The error's offset with a caret under it, or just the kind when the error
+// didn't carry an offset.
+fn annotated(
+    origin: &str,
+    source: &str,
+    offset: Option<usize>,
+    kind: Option<&str>,
+    color: bool,
+) -> String {
+    let kind = kind.unwrap_or("error");
+    let Some(offset) = offset else {
+        return kind.to_string();
+    };
+
+    // An error at end of input needs something to point at.
+    let mut source = source.to_string();
+    if offset >= source.len() {
+        source.push(' ');
+    }
+    let mut start = offset.min(source.len() - 1);
+    while !source.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = start + 1;
+    while !source.is_char_boundary(end) {
+        end += 1;
+    }
+
+    let message = Level::Error.title(kind).snippet(
+        Snippet::source(&source)
+            .line_start(1)
+            .origin(origin)
+            .fold(true)
+            .annotation(Level::Error.span(start..end)),
+    );
+    let renderer = if color {
+        Renderer::styled()
+    } else {
+        Renderer::plain()
+    };
+    // Not `.to_string()`: the rendered value borrows `renderer`, and as a
+    // tail expression it would outlive it.
+    format!("{}", renderer.render(message))
+}
@@ -0,0 +1,260 @@
+use rusqlite::{Connection, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::db::load_result_by_id;
+use crate::worker::OUTCOME_COMPLETED;
+use crate::ParseData;
+
+// Failure kinds `export-snapshots` can select on.
+pub const FAILURE_KINDS: &[&str] = &[
+    "parse",
+    "reparse",
+    "syntax",
+    "idempotence",
+    "comments",
+    "hygiene",
+    "crash",
+];
+
+fn failure_condition(kind: &str) -> String {
+    match kind {
+        "parse" => "error IS NOT NULL".to_string(),
+        "reparse" => "reparse_error IS NOT NULL".to_string(),
+        "syntax" => "fmt_changed_syntax = 1".to_string(),
+        "idempotence" => "fmt_idempotent = 0".to_string(),
+        "comments" => "comments_preserved = 0".to_string(),
+        "hygiene" => "fmt_hygienic = 0".to_string(),
+        _ => format!(
+            "panic_stage IS NOT NULL OR coalesce(outcome, '{0}') != '{0}'",
+            OUTCOME_COMPLETED
+        ),
+    }
+}
+
+pub struct ExportOptions<'a> {
+    pub out: &'a Path,
+    pub failures: &'a [String],
+    pub ids: &'a [i64],
+    pub limit: Option<usize>,
+}
+
+// test_syntax names snapshots `<name>.<kind>.roc`, where the kind says which
+// parser entrypoint reads it.
+fn snapshot_kind(data: &ParseData) -> &'static str {
+    match data.entrypoint.as_deref() {
+        Some("defs") => "moduledefs",
+        Some("expr") => "expr",
+        _ => "full",
+    }
+}
+
+pub fn export_snapshots(conn: &Connection, run_id: i64, options: &ExportOptions) -> Result<()> {
+    let mut conditions: Vec<String> = options
+        .failures
+        .iter()
+        .map(|kind| format!("({})", failure_condition(kind)))
+        .collect();
+    // Files roc rightly rejects would swamp everything else, so without an
+    // explicit choice (of failures or of rows) only the failures that are
+    // clearly bugs go out.
+    if conditions.is_empty() && options.ids.is_empty() {
+        conditions = FAILURE_KINDS[1..]
+            .iter()
+            .map(|kind| format!("({})", failure_condition(kind)))
+            .collect();
+    }
+    let mut sql = "SELECT id, repo_url, file_path, contents FROM roc_parse_results
+         WHERE run_id = ?1"
+        .to_string();
+    if !conditions.is_empty() {
+        sql.push_str(&format!(" AND ({})", conditions.join(" OR ")));
+    }
+    if !options.ids.is_empty() {
+        let ids = options
+            .ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        sql.push_str(&format!(" AND id IN ({})", ids));
+    }
+    sql.push_str(" ORDER BY id");
+
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt
+        .query_map([run_id], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut names = existing_names(options.out);
+    let mut written = 0;
+    let mut without_result = 0;
+    for (id, repo_url, file_path, contents) in rows {
+        if options.limit.is_some_and(|limit| written >= limit) {
+            break;
+        }
+        let Some(data) = load_result_by_id(conn, id)? else {
+            continue;
+        };
+
+        let name = unique_name(&mut names, &snapshot_name(&repo_url, &file_path));
+        match write_snapshot(options.out, &name, &contents, &data) {
+            Ok((path, has_result)) => {
+                println!("{} <- {} {}", path.display(), repo_url, file_path);
+                if data.output.is_none() && !has_result {
+                    println!(
+                        "  {}, not a parse error: it has no .result-ast",
+                        describe_non_parse_failure(&data)
+                    );
+                }
+                written += 1;
+                without_result += usize::from(!has_result);
+            }
+            Err(e) => {
+                eprintln!("Failed to write snapshot {}: {}", name, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    println!(
+        "Exported {} snapshots to {}",
+        written,
+        options.out.display()
+    );
+    if without_result > 0 {
+        println!(
+            "{} of them have no .result-ast; generate those (and check every \
+             .formatted.roc) in roc with ROC_SNAPSHOT_TEST_OVERWRITE=1 cargo test -p test_syntax",
+            without_result
+        );
+    }
+    Ok(())
+}
+
+fn describe_non_parse_failure(data: &ParseData) -> String {
+    match (&data.panic_stage, &data.outcome) {
+        (Some(stage), _) => format!("panic in {}", stage),
+        (None, Some(outcome)) => outcome.clone(),
+        (None, None) => "no output".to_string(),
+    }
+}
+
+// Where a case goes follows test_syntax: input roc rejects is `fail` and
+// gets the error as its `.result-ast`; input that parses to Malformed nodes is
+// `malformed`; everything else parsed fine and is `pass`, which for these
+// cases means the formatter is what's broken.
+//
+// Only a parse error is recorded in the form test_syntax expects. `output` is
+// osprey's own dump of the AST, and a file whose parse panicked, hung or ran
+// out of memory has no result at all, so those get no `.result-ast` and
+// `export_snapshots` says to generate it in roc. Returns the input's path and
+// whether its `.result-ast` was written.
+fn write_snapshot(
+    out: &Path,
+    name: &str,
+    contents: &str,
+    data: &ParseData,
+) -> io::Result<(PathBuf, bool)> {
+    let kind = snapshot_kind(data);
+    let subdir = match &data.output {
+        None => "fail",
+        Some(output) if output.contains("Malformed") => "malformed",
+        Some(_) => "pass",
+    };
+    let dir = out.join(subdir);
+    fs::create_dir_all(&dir)?;
+    let file = |extension: &str| dir.join(format!("{}.{}.{}", name, kind, extension));
+
+    let input = file("roc");
+    fs::write(&input, contents)?;
+
+    if let Some(formatted) = &data.fmt_output {
+        fs::write(file("formatted.roc"), formatted)?;
+    }
+    let has_result = match (&data.output, &data.error) {
+        (None, Some(error)) => {
+            fs::write(file("result-ast"), format!("{}\n", error))?;
+            true
+        }
+        _ => false,
+    };
+
+    Ok((input, has_result))
+}
+
+// Snapshot names end up as Rust test function names, so only lowercase
+// alphanumerics and underscores survive.
+fn snapshot_name(repo_url: &str, file_path: &str) -> String {
+    let repo = repo_url
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or("");
+    let stem = Path::new(file_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut name = String::from("osprey");
+    for part in [repo, stem.as_str()] {
+        name.push('_');
+        let mut last_underscore = true;
+        let mut last_lowercase = false;
+        for c in part.chars() {
+            if c.is_ascii_alphanumeric() {
+                if c.is_ascii_uppercase() && last_lowercase {
+                    name.push('_');
+                }
+                name.push(c.to_ascii_lowercase());
+                last_underscore = false;
+                last_lowercase = c.is_ascii_lowercase() || c.is_ascii_digit();
+            } else if !last_underscore {
+                name.push('_');
+                last_underscore = true;
+                last_lowercase = false;
+            }
+        }
+        while name.ends_with('_') {
+            name.pop();
+        }
+    }
+    name
+}
+
+// Exporting straight into roc's snapshot directory mustn't clobber the
+// snapshots already there.
+fn existing_names(out: &Path) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for subdir in ["pass", "fail", "malformed"] {
+        let Ok(entries) = fs::read_dir(out.join(subdir)) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(name) = file_name.split('.').next() {
+                names.insert(name.to_string());
+            }
+        }
+    }
+    names
+}
+
+fn unique_name(names: &mut HashSet<String>, name: &str) -> String {
+    let mut candidate = name.to_string();
+    let mut n = 2;
+    while !names.insert(candidate.clone()) {
+        candidate = format!("{}_{}", name, n);
+        n += 1;
+    }
+    candidate
+}
@@ -0,0 +1,546 @@
+use rusqlite::{params, Connection, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::diff::OutputFormat;
+use crate::worker::OUTCOME_COMPLETED;
+
+#[derive(Serialize)]
+pub struct Counts {
+    pub files: i64,
+    pub parse_failures: i64,
+    pub reparse_failures: i64,
+    pub not_idempotent: i64,
+    pub changed_syntax: i64,
+    pub comments_not_preserved: i64,
+    pub unhygienic: i64,
+    pub panics: i64,
+    pub abnormal_outcomes: i64,
+    // Files failing any of the above
+    pub failing: i64,
+}
+
+impl Counts {
+    // Everything `stats` reports on, in the order it reports them.
+    fn metrics(&self) -> [(&'static str, &'static str, i64); 8] {
+        [
+            ("parse_failures", "parse failures", self.parse_failures),
+            (
+                "reparse_failures",
+                "reparse failures",
+                self.reparse_failures,
+            ),
+            ("not_idempotent", "fmt not idempotent", self.not_idempotent),
+            ("changed_syntax", "fmt changes syntax", self.changed_syntax),
+            (
+                "comments_not_preserved",
+                "fmt breaks comments",
+                self.comments_not_preserved,
+            ),
+            ("unhygienic", "fmt output unclean", self.unhygienic),
+            ("panics", "panics", self.panics),
+            (
+                "abnormal_outcomes",
+                "timeout/oom/crash",
+                self.abnormal_outcomes,
+            ),
+        ]
+    }
+
+    fn percentages(&self) -> BTreeMap<&'static str, f64> {
+        self.metrics()
+            .iter()
+            .filter(|_| self.files > 0)
+            .map(|&(key, _, n)| (key, 100.0 * n as f64 / self.files as f64))
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct Totals {
+    #[serde(flatten)]
+    pub counts: Counts,
+    pub percentages: BTreeMap<&'static str, f64>,
+}
+
+impl Totals {
+    fn new(counts: Counts) -> Totals {
+        Totals {
+            percentages: counts.percentages(),
+            counts,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RepoCounts {
+    pub repo_url: String,
+    #[serde(flatten)]
+    pub counts: Counts,
+}
+
+// Files that didn't parse at all have no entrypoint, and are counted under
+// "(none)".
+#[derive(Serialize)]
+pub struct EntrypointCounts {
+    pub entrypoint: String,
+    #[serde(flatten)]
+    pub counts: Counts,
+}
+
+#[derive(Serialize)]
+pub struct ErrorKindCount {
+    pub kind: String,
+    pub files: i64,
+}
+
+// The same checks run on each top-level def on its own
+#[derive(Serialize)]
+pub struct DefCounts {
+    pub defs: i64,
+    pub parse_failures: i64,
+    pub reparse_failures: i64,
+    pub changed_syntax: i64,
+    pub not_idempotent: i64,
+    pub panics: i64,
+    // Defs failing any of the above
+    pub failing: i64,
+}
+
+// How repeated formatting ended for files that aren't idempotent
+#[derive(Serialize)]
+pub struct ConvergenceCount {
+    pub convergence: String,
+    pub files: i64,
+    // Files whose output got bigger on every pass
+    pub growing: i64,
+    pub max_passes: Option<i64>,
+    pub max_cycle_length: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+    pub run_id: i64,
+    #[serde(flatten)]
+    pub totals: Totals,
+    // The same, counting each distinct file content once
+    pub unique: Totals,
+    pub entrypoints: Vec<EntrypointCounts>,
+    pub repos: Vec<RepoCounts>,
+    pub parse_error_kinds: Vec<ErrorKindCount>,
+    pub reparse_error_kinds: Vec<ErrorKindCount>,
+    pub convergence: Vec<ConvergenceCount>,
+    pub defs: DefCounts,
+    pub arena: ArenaStats,
+}
+
+#[derive(Serialize)]
+pub struct ArenaStats {
+    pub source_bytes: i64,
+    pub arena_bytes_parse: i64,
+    pub arena_bytes_reparse: i64,
+    pub parse_bytes_per_source_byte: Option<f64>,
+    pub reparse_bytes_per_source_byte: Option<f64>,
+    pub worst: Vec<ArenaFile>,
+}
+
+#[derive(Serialize)]
+pub struct ArenaFile {
+    pub repo_url: String,
+    pub file_path: String,
+    pub source_bytes: i64,
+    pub arena_bytes_parse: i64,
+    pub parse_bytes_per_source_byte: f64,
+}
+
+// bumpalo's first chunk alone is bigger than a small file, so ratios for
+// those say nothing about the parser; they're left out of the worst list.
+const ARENA_MIN_SOURCE_BYTES: i64 = 1024;
+
+const COUNT_COLUMNS: &str = "count(*),
+    count(error),
+    count(reparse_error),
+    coalesce(sum(fmt_idempotent = 0), 0),
+    coalesce(sum(fmt_changed_syntax = 1), 0),
+    coalesce(sum(comments_preserved = 0), 0),
+    coalesce(sum(fmt_hygienic = 0), 0),
+    count(panic_stage),
+    coalesce(sum(outcome IS NOT NULL AND outcome != ?2), 0),
+    coalesce(sum(error IS NOT NULL OR reparse_error IS NOT NULL OR fmt_idempotent = 0
+        OR fmt_changed_syntax = 1 OR comments_preserved = 0 OR fmt_hygienic = 0
+        OR panic_stage IS NOT NULL OR (outcome IS NOT NULL AND outcome != ?2)), 0)";
+
+fn counts(row: &rusqlite::Row, first: usize) -> Result<Counts> {
+    Ok(Counts {
+        files: row.get(first)?,
+        parse_failures: row.get(first + 1)?,
+        reparse_failures: row.get(first + 2)?,
+        not_idempotent: row.get(first + 3)?,
+        changed_syntax: row.get(first + 4)?,
+        comments_not_preserved: row.get(first + 5)?,
+        unhygienic: row.get(first + 6)?,
+        panics: row.get(first + 7)?,
+        abnormal_outcomes: row.get(first + 8)?,
+        failing: row.get(first + 9)?,
+    })
+}
+
+pub fn compute_stats(conn: &Connection, run_id: i64, top: usize) -> Result<Stats> {
+    let totals = conn.query_row(
+        &format!(
+            "SELECT {} FROM roc_parse_results WHERE run_id = ?1",
+            COUNT_COLUMNS
+        ),
+        params![run_id, OUTCOME_COMPLETED],
+        |row| counts(row, 0),
+    )?;
+    let unique = conn.query_row(
+        &format!(
+            "SELECT {} FROM roc_parse_results WHERE run_id = ?1 AND duplicate_of IS NULL",
+            COUNT_COLUMNS
+        ),
+        params![run_id, OUTCOME_COMPLETED],
+        |row| counts(row, 0),
+    )?;
+
+    // Rows from before `entrypoint` existed have it NULL, and only ever
+    // parsed as modules.
+    let mut stmt = conn.prepare(&format!(
+        "SELECT coalesce(entrypoint, CASE WHEN output IS NOT NULL THEN 'module' END, '(none)'),
+            {}
+         FROM roc_parse_results WHERE run_id = ?1
+         GROUP BY 1
+         ORDER BY count(*) DESC, 1",
+        COUNT_COLUMNS
+    ))?;
+    let entrypoints = stmt
+        .query_map(params![run_id, OUTCOME_COMPLETED], |row| {
+            Ok(EntrypointCounts {
+                entrypoint: row.get(0)?,
+                counts: counts(row, 1)?,
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT repo_url, {} FROM roc_parse_results WHERE run_id = ?1
+         GROUP BY repo_url",
+        COUNT_COLUMNS
+    ))?;
+    let mut repos = stmt
+        .query_map(params![run_id, OUTCOME_COMPLETED], |row| {
+            Ok(RepoCounts {
+                repo_url: row.get(0)?,
+                counts: counts(row, 1)?,
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    repos.sort_by(|a, b| {
+        b.counts
+            .failing
+            .cmp(&a.counts.failing)
+            .then_with(|| a.repo_url.cmp(&b.repo_url))
+    });
+
+    Ok(Stats {
+        run_id,
+        totals: Totals::new(totals),
+        unique: Totals::new(unique),
+        entrypoints,
+        repos,
+        parse_error_kinds: error_kinds(conn, run_id, "error", top)?,
+        reparse_error_kinds: error_kinds(conn, run_id, "reparse_error", top)?,
+        convergence: convergence(conn, run_id)?,
+        defs: def_counts(conn, run_id)?,
+        arena: arena_stats(conn, run_id, top)?,
+    })
+}
+
+fn arena_stats(conn: &Connection, run_id: i64, top: usize) -> Result<ArenaStats> {
+    // length() counts characters in TEXT, the arena is measured in bytes
+    let (source_bytes, arena_bytes_parse, reparse_source_bytes, arena_bytes_reparse) = conn
+        .query_row(
+            "SELECT
+            coalesce(sum(length(CAST(contents AS BLOB))), 0),
+            coalesce(sum(arena_bytes_parse), 0),
+            coalesce(sum(CASE WHEN arena_bytes_reparse IS NOT NULL
+                         THEN length(CAST(contents AS BLOB)) END), 0),
+            coalesce(sum(arena_bytes_reparse), 0)
+         FROM roc_parse_results WHERE run_id = ?1 AND arena_bytes_parse IS NOT NULL",
+            [run_id],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                ))
+            },
+        )?;
+
+    let mut stmt = conn.prepare(
+        "SELECT repo_url, file_path, length(CAST(contents AS BLOB)) AS source_bytes,
+            arena_bytes_parse
+         FROM roc_parse_results
+         WHERE run_id = ?1 AND arena_bytes_parse IS NOT NULL AND source_bytes >= ?2
+         ORDER BY CAST(arena_bytes_parse AS REAL) / source_bytes DESC
+         LIMIT ?3",
+    )?;
+    let worst = stmt
+        .query_map(params![run_id, ARENA_MIN_SOURCE_BYTES, top as i64], |row| {
+            let source_bytes: i64 = row.get(2)?;
+            let arena_bytes_parse: i64 = row.get(3)?;
+            Ok(ArenaFile {
+                repo_url: row.get(0)?,
+                file_path: row.get(1)?,
+                source_bytes,
+                arena_bytes_parse,
+                parse_bytes_per_source_byte: arena_bytes_parse as f64 / source_bytes as f64,
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    let ratio = |arena: i64, source: i64| (source > 0).then(|| arena as f64 / source as f64);
+    Ok(ArenaStats {
+        source_bytes,
+        arena_bytes_parse,
+        arena_bytes_reparse,
+        parse_bytes_per_source_byte: ratio(arena_bytes_parse, source_bytes),
+        reparse_bytes_per_source_byte: ratio(arena_bytes_reparse, reparse_source_bytes),
+        worst,
+    })
+}
+
+// Rows written before errors were broken down have no kind; they're grouped
+// together rather than dropped so the counts still add up.
+fn error_kinds(
+    conn: &Connection,
+    run_id: i64,
+    column: &str,
+    top: usize,
+) -> Result<Vec<ErrorKindCount>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT coalesce({column}_kind, '(unknown)'), count(*) FROM roc_parse_results
+         WHERE run_id = ?1 AND {column} IS NOT NULL
+         GROUP BY 1
+         ORDER BY 2 DESC, 1
+         LIMIT ?2",
+        column = column
+    ))?;
+    let rows = stmt.query_map(params![run_id, top as i64], |row| {
+        Ok(ErrorKindCount {
+            kind: row.get(0)?,
+            files: row.get(1)?,
+        })
+    })?;
+    rows.collect()
+}
+
+fn def_counts(conn: &Connection, run_id: i64) -> Result<DefCounts> {
+    conn.query_row(
+        "SELECT count(*),
+            count(d.error_kind),
+            count(d.reparse_error_kind),
+            coalesce(sum(d.fmt_changed_syntax = 1), 0),
+            coalesce(sum(d.fmt_idempotent = 0), 0),
+            count(d.panic_message),
+            coalesce(sum(d.error_kind IS NOT NULL OR d.reparse_error_kind IS NOT NULL
+                OR d.fmt_changed_syntax = 1 OR d.fmt_idempotent = 0
+                OR d.panic_message IS NOT NULL), 0)
+         FROM def_results d JOIN roc_parse_results r ON d.result_id = r.id
+         WHERE r.run_id = ?1",
+        [run_id],
+        |row| {
+            Ok(DefCounts {
+                defs: row.get(0)?,
+                parse_failures: row.get(1)?,
+                reparse_failures: row.get(2)?,
+                changed_syntax: row.get(3)?,
+                not_idempotent: row.get(4)?,
+                panics: row.get(5)?,
+                failing: row.get(6)?,
+            })
+        },
+    )
+}
+
+fn convergence(conn: &Connection, run_id: i64) -> Result<Vec<ConvergenceCount>> {
+    let mut stmt = conn.prepare(
+        "SELECT coalesce(fmt_convergence, '(unknown)'), count(*),
+            coalesce(sum(fmt_grows = 1), 0), max(fmt_passes_to_converge), max(fmt_cycle_length)
+         FROM roc_parse_results
+         WHERE run_id = ?1 AND fmt_idempotent = 0
+         GROUP BY 1
+         ORDER BY 2 DESC, 1",
+    )?;
+    let rows = stmt.query_map([run_id], |row| {
+        Ok(ConvergenceCount {
+            convergence: row.get(0)?,
+            files: row.get(1)?,
+            growing: row.get(2)?,
+            max_passes: row.get(3)?,
+            max_cycle_length: row.get(4)?,
+        })
+    })?;
+    rows.collect()
+}
+
+pub fn report_stats(stats: &Stats, format: OutputFormat) {
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string(stats).unwrap());
+        return;
+    }
+
+    let totals = &stats.totals;
+    let unique = &stats.unique;
+    println!(
+        "Run {}: {} files, {} unique contents",
+        stats.run_id, totals.counts.files, unique.counts.files
+    );
+    println!();
+    println!(
+        "{:<20} {:>8} {:>8} {:>8} {:>8}",
+        "", "files", "", "unique", ""
+    );
+    let percent = |totals: &Totals, key| match totals.percentages.get(key) {
+        Some(percent) => format!("{:.2}%", percent),
+        None => "-".to_string(),
+    };
+    for ((key, label, n), (_, _, unique_n)) in totals
+        .counts
+        .metrics()
+        .into_iter()
+        .zip(unique.counts.metrics())
+    {
+        println!(
+            "{:<20} {:>8} {:>8} {:>8} {:>8}",
+            label,
+            n,
+            percent(totals, key),
+            unique_n,
+            percent(unique, key)
+        );
+    }
+
+    for (title, kinds) in [
+        ("parse error kinds", &stats.parse_error_kinds),
+        ("reparse error kinds", &stats.reparse_error_kinds),
+    ] {
+        if kinds.is_empty() {
+            continue;
+        }
+        println!();
+        println!("{:<40} {:>8}", title, "files");
+        for kind in kinds {
+            println!("{:<40} {:>8}", kind.kind, kind.files);
+        }
+    }
+
+    if !stats.convergence.is_empty() {
+        let max = |n: Option<i64>| n.map_or("-".to_string(), |n| n.to_string());
+        println!();
+        println!(
+            "{:<24} {:>8} {:>8} {:>8} {:>8}",
+            "fmt convergence", "files", "growing", "passes", "cycle"
+        );
+        for count in &stats.convergence {
+            println!(
+                "{:<24} {:>8} {:>8} {:>8} {:>8}",
+                count.convergence,
+                count.files,
+                count.growing,
+                max(count.max_passes),
+                max(count.max_cycle_length)
+            );
+        }
+    }
+
+    let defs = &stats.defs;
+    if defs.defs > 0 {
+        let percent = |n: i64| format!("{:.2}%", 100.0 * n as f64 / defs.defs as f64);
+        println!();
+        println!("{:<24} {:>8} {:>8}", "top-level defs", defs.defs, "");
+        for (label, n) in [
+            ("parse failures", defs.parse_failures),
+            ("reparse failures", defs.reparse_failures),
+            ("fmt changes syntax", defs.changed_syntax),
+            ("fmt not idempotent", defs.not_idempotent),
+            ("panics", defs.panics),
+            ("failing any check", defs.failing),
+        ] {
+            println!("{:<24} {:>8} {:>8}", label, n, percent(n));
+        }
+    }
+
+    let arena = &stats.arena;
+    let ratio = |ratio: Option<f64>| match ratio {
+        Some(ratio) => format!("{:.1}", ratio),
+        None => "-".to_string(),
+    };
+    println!();
+    println!(
+        "arena bytes per source byte: {} after parse, {} after reparse",
+        ratio(arena.parse_bytes_per_source_byte),
+        ratio(arena.reparse_bytes_per_source_byte)
+    );
+    if !arena.worst.is_empty() {
+        println!();
+        println!(
+            "{:<64} {:>10} {:>12} {:>8}",
+            "most arena per source byte", "bytes", "arena", "ratio"
+        );
+        for file in &arena.worst {
+            println!(
+                "{:<64} {:>10} {:>12} {:>8.1}",
+                format!("{} {}", file.repo_url, file.file_path),
+                file.source_bytes,
+                file.arena_bytes_parse,
+                file.parse_bytes_per_source_byte
+            );
+        }
+    }
+
+    print_counts(
+        "entrypoint",
+        stats
+            .entrypoints
+            .iter()
+            .map(|e| (e.entrypoint.as_str(), &e.counts)),
+    );
+    print_counts(
+        "repo",
+        stats.repos.iter().map(|r| (r.repo_url.as_str(), &r.counts)),
+    );
+}
+
+fn print_counts<'a>(title: &str, rows: impl Iterator<Item = (&'a str, &'a Counts)>) {
+    println!();
+    println!(
+        "{:<48} {:>6} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7}",
+        title,
+        "files",
+        "parse",
+        "reparse",
+        "idemp",
+        "syntax",
+        "comment",
+        "hygiene",
+        "panic",
+        "abnorm"
+    );
+    for (name, c) in rows {
+        println!(
+            "{:<48} {:>6} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7} {:>7}",
+            name,
+            c.files,
+            c.parse_failures,
+            c.reparse_failures,
+            c.not_idempotent,
+            c.changed_syntax,
+            c.comments_not_preserved,
+            c.unhygienic,
+            c.panics,
+            c.abnormal_outcomes
+        );
+    }
+}
@@ -0,0 +1,328 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::{parse_one, ParseData};
+
+pub const OUTCOME_COMPLETED: &str = "completed";
+pub const OUTCOME_TIMEOUT: &str = "timeout";
+pub const OUTCOME_OOM: &str = "oom";
+pub const OUTCOME_STACK_OVERFLOW: &str = "stack_overflow";
+pub const OUTCOME_CRASH: &str = "crash";
+
+// How much of a dead worker's stderr to keep for `outcome_details`.
+const STDERR_TAIL_BYTES: usize = 4096;
+
+#[derive(Clone, Debug, Default)]
+pub struct WorkerLimits {
+    pub timeout: Option<Duration>,
+    pub stack_size: Option<usize>,
+    pub memory_limit: Option<usize>,
+    // Not a resource limit, but like the others it has to reach the child
+    pub max_fmt_passes: Option<usize>,
+}
+
+impl WorkerLimits {
+    fn args(&self) -> Vec<String> {
+        let mut args = vec!["worker".to_string()];
+        if let Some(stack_size) = self.stack_size {
+            args.push("--stack-size".to_string());
+            args.push(stack_size.to_string());
+        }
+        if let Some(memory_limit) = self.memory_limit {
+            args.push("--memory-limit".to_string());
+            args.push(memory_limit.to_string());
+        }
+        if let Some(max_fmt_passes) = self.max_fmt_passes {
+            args.push("--max-fmt-passes".to_string());
+            args.push(max_fmt_passes.to_string());
+        }
+        args
+    }
+}
+
+// A global allocator that refuses allocations once the process has more than
+// `MEMORY_LIMIT` bytes live. It only counts while a limit is set, so the
+// in-process parse path pays nothing but a relaxed load. bumpalo turns a
+// refused chunk into a panic, which `parse_one` would otherwise report as an
+// ordinary panic; `LIMIT_HIT` lets the worker tell the two apart.
+pub struct LimitedAlloc;
+
+static MEMORY_LIMIT: AtomicIsize = AtomicIsize::new(0);
+static ALLOCATED: AtomicIsize = AtomicIsize::new(0);
+static LIMIT_HIT: AtomicBool = AtomicBool::new(false);
+
+// Capturing a backtrace allocates, and if that allocation fails while the
+// backtrace lock is held the process deadlocks instead of aborting.
+pub fn memory_limit_hit() -> bool {
+    LIMIT_HIT.load(Ordering::Relaxed)
+}
+
+impl LimitedAlloc {
+    fn reserve(&self, size: usize) -> bool {
+        let limit = MEMORY_LIMIT.load(Ordering::Relaxed);
+        if limit == 0 {
+            return true;
+        }
+        let size = size as isize;
+        if ALLOCATED.fetch_add(size, Ordering::Relaxed) + size > limit {
+            ALLOCATED.fetch_sub(size, Ordering::Relaxed);
+            LIMIT_HIT.store(true, Ordering::Relaxed);
+            false
+        } else {
+            true
+        }
+    }
+
+    fn release(&self, size: usize) {
+        if MEMORY_LIMIT.load(Ordering::Relaxed) != 0 {
+            ALLOCATED.fetch_sub(size as isize, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for LimitedAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !self.reserve(layout.size()) {
+            return std::ptr::null_mut();
+        }
+        let ptr = System.alloc(layout);
+        if ptr.is_null() {
+            self.release(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        self.release(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size > layout.size() && !self.reserve(new_size - layout.size()) {
+            return std::ptr::null_mut();
+        }
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() {
+            if new_size > layout.size() {
+                self.release(new_size - layout.size());
+            }
+        } else if new_size < layout.size() {
+            self.release(layout.size() - new_size);
+        }
+        new_ptr
+    }
+}
+
+// Child side of the protocol: one JSON-encoded source string per line on
+// stdin, one JSON-encoded `ParseData` per line on stdout.
+pub fn run_worker(stack_size: Option<usize>, memory_limit: Option<usize>) -> io::Result<()> {
+    if let Some(memory_limit) = memory_limit {
+        MEMORY_LIMIT.store(memory_limit as isize, Ordering::Relaxed);
+    }
+
+    let mut builder = thread::Builder::new().name("osprey_parse worker".to_string());
+    if let Some(stack_size) = stack_size {
+        builder = builder.stack_size(stack_size);
+    }
+
+    builder.spawn(worker_loop)?.join().unwrap()
+}
+
+fn worker_loop() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout().lock();
+
+    for line in stdin.lock().lines() {
+        let contents: String = serde_json::from_str(&line?)?;
+
+        LIMIT_HIT.store(false, Ordering::Relaxed);
+        let mut result = parse_one(&contents);
+        if LIMIT_HIT.load(Ordering::Relaxed) {
+            result.outcome = Some(OUTCOME_OOM.to_string());
+        }
+
+        serde_json::to_writer(&mut stdout, &result)?;
+        stdout.write_all(b"\n")?;
+        stdout.flush()?;
+    }
+
+    Ok(())
+}
+
+struct RunningWorker {
+    child: Child,
+    stdin: ChildStdin,
+    results: Receiver<io::Result<ParseData>>,
+    stderr: JoinHandle<()>,
+    // Cleared as each request is sent, so it only ever holds what the child
+    // printed while working on the current file
+    stderr_tail: Arc<Mutex<Vec<u8>>>,
+}
+
+impl RunningWorker {
+    fn spawn(limits: &WorkerLimits) -> io::Result<RunningWorker> {
+        let mut child = Command::new(std::env::current_exe()?)
+            .args(limits.args())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+
+        let (results_tx, results) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let result = line.and_then(|line| Ok(serde_json::from_str(&line)?));
+                if results_tx.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // The child's stderr has to be drained continuously or a chatty
+        // panic hook could fill the pipe and wedge the worker. Pass it
+        // through, and keep the tail around to classify a crash.
+        let stderr_tail = Arc::new(Mutex::new(Vec::new()));
+        let tail = Arc::clone(&stderr_tail);
+        let stderr = thread::spawn(move || {
+            let mut reader = BufReader::new(stderr);
+            let mut buf = [0; 4096];
+            while let Ok(n) = reader.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                let _ = io::stderr().write_all(&buf[..n]);
+                let mut tail = tail.lock().unwrap();
+                tail.extend_from_slice(&buf[..n]);
+                if tail.len() > STDERR_TAIL_BYTES {
+                    let excess = tail.len() - STDERR_TAIL_BYTES;
+                    tail.drain(..excess);
+                }
+            }
+        });
+
+        Ok(RunningWorker {
+            child,
+            stdin,
+            results,
+            stderr,
+            stderr_tail,
+        })
+    }
+
+    fn kill(mut self) -> String {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = self.stderr.join();
+        let tail = self.stderr_tail.lock().unwrap();
+        String::from_utf8_lossy(&tail).into_owned()
+    }
+}
+
+// Runs `parse_one` in a child process, so that infinite loops, stack
+// overflows and runaway allocations in roc_parse only cost us that one file.
+// The child is reused across files and respawned after it dies.
+pub struct WorkerProcess {
+    limits: WorkerLimits,
+    running: Option<RunningWorker>,
+}
+
+impl WorkerProcess {
+    pub fn new(limits: WorkerLimits) -> WorkerProcess {
+        WorkerProcess {
+            limits,
+            running: None,
+        }
+    }
+
+    pub fn parse(&mut self, contents: &str) -> ParseData {
+        match self.try_parse(contents) {
+            Ok(result) => result,
+            Err(e) => {
+                if let Some(worker) = self.running.take() {
+                    worker.kill();
+                }
+                failed(OUTCOME_CRASH, format!("worker error: {}", e))
+            }
+        }
+    }
+
+    fn try_parse(&mut self, contents: &str) -> io::Result<ParseData> {
+        if self.running.is_none() {
+            self.running = Some(RunningWorker::spawn(&self.limits)?);
+        }
+        let worker = self.running.as_mut().unwrap();
+
+        let mut request = serde_json::to_string(contents)?;
+        request.push('\n');
+        worker.stderr_tail.lock().unwrap().clear();
+        // If the child already died the write fails with a broken pipe; the
+        // disconnected results channel below tells us what happened.
+        let _ = worker
+            .stdin
+            .write_all(request.as_bytes())
+            .and_then(|_| worker.stdin.flush());
+
+        let received = match self.limits.timeout {
+            Some(timeout) => worker.results.recv_timeout(timeout),
+            None => worker
+                .results
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match received {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                self.running.take().unwrap().kill();
+                Ok(failed(
+                    OUTCOME_TIMEOUT,
+                    format!("killed after {:?}", self.limits.timeout.unwrap()),
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                let stderr = self.running.take().unwrap().kill();
+                Ok(classify_crash(stderr))
+            }
+        }
+    }
+}
+
+impl Drop for WorkerProcess {
+    fn drop(&mut self) {
+        if let Some(worker) = self.running.take() {
+            worker.kill();
+        }
+    }
+}
+
+fn failed(outcome: &str, details: String) -> ParseData {
+    ParseData {
+        outcome: Some(outcome.to_string()),
+        outcome_details: Some(details),
+        ..ParseData::default()
+    }
+}
+
+// Allocation failures outside of a bump arena and stack overflows both abort
+// the process, so the only record of which one it was is what the Rust
+// runtime printed on the way down.
+fn classify_crash(stderr: String) -> ParseData {
+    let outcome = if stderr.contains("memory allocation of") {
+        OUTCOME_OOM
+    } else if stderr.contains("has overflowed its stack") {
+        OUTCOME_STACK_OVERFLOW
+    } else {
+        OUTCOME_CRASH
+    };
+    failed(outcome, stderr)
+}